@@ -0,0 +1,191 @@
+use std::io::Write;
+
+use bstr::ByteSlice;
+
+pub const SNAPSHOT_REF: &str = "refs/branch-stash/snapshot";
+
+const BUNDLE_V2_SIGNATURE: &[u8] = b"# v2 git bundle\n";
+const BUNDLE_V3_SIGNATURE: &[u8] = b"# v3 git bundle\n";
+
+pub fn export(
+    repo: &git2::Repository,
+    snapshot: &crate::Snapshot,
+    path: &std::path::Path,
+) -> eyre::Result<()> {
+    let missing: Vec<_> = snapshot
+        .branches
+        .iter()
+        .filter(|b| repo.find_commit(b.id).is_err())
+        .map(|b| format!("{} ({})", b.name, b.id))
+        .collect();
+    if !missing.is_empty() {
+        eyre::bail!(
+            "snapshot references commits missing from the repository: {}",
+            missing.join(", ")
+        );
+    }
+
+    let json = serde_json::to_vec_pretty(snapshot)?;
+    let snapshot_id = repo.blob(&json)?;
+
+    let mut walk = repo.revwalk()?;
+    for branch in snapshot.branches.iter() {
+        walk.push(branch.id)?;
+    }
+    let mut builder = repo.packbuilder()?;
+    builder.insert_walk(&mut walk)?;
+    builder.insert_object(snapshot_id, None)?;
+    let mut pack = git2::Buf::new();
+    builder.write_buf(&mut pack)?;
+    log::trace!(
+        "Packed {} objects for {} branches",
+        builder.object_count(),
+        snapshot.branches.len()
+    );
+
+    let mut bundle = Vec::new();
+    bundle.extend_from_slice(BUNDLE_V2_SIGNATURE);
+    for branch in snapshot.branches.iter() {
+        writeln!(bundle, "{} refs/heads/{}", branch.id, branch.name)?;
+    }
+    writeln!(bundle, "{} {}", snapshot_id, SNAPSHOT_REF)?;
+    writeln!(bundle)?;
+    bundle.extend_from_slice(&pack);
+
+    std::fs::write(path, &bundle)?;
+    Ok(())
+}
+
+pub fn import(repo: &git2::Repository, path: &std::path::Path) -> eyre::Result<crate::Snapshot> {
+    let bundle = std::fs::read(path)?;
+    let Bundle { refs, pack } = parse_bundle(repo, &bundle)?;
+    let snapshot_id = refs
+        .iter()
+        .find(|(_, name)| name == SNAPSHOT_REF)
+        .map(|(id, _)| *id)
+        .ok_or_else(|| eyre::eyre!("{} is not a branch-stash bundle", path.display()))?;
+
+    let odb = repo.odb()?;
+    let mut writer = odb.packwriter()?;
+    writer.write_all(pack)?;
+    writer.commit()?;
+    log::trace!("Unpacked {} refs from {}", refs.len(), path.display());
+
+    let blob = repo.find_blob(snapshot_id)?;
+    let snapshot = crate::Snapshot::from_slice(blob.content())?;
+
+    let missing: Vec<_> = snapshot
+        .branches
+        .iter()
+        .filter(|b| repo.find_commit(b.id).is_err())
+        .map(|b| format!("{} ({})", b.name, b.id))
+        .collect();
+    if !missing.is_empty() {
+        eyre::bail!(
+            "bundle is missing commits referenced by the snapshot: {}",
+            missing.join(", ")
+        );
+    }
+
+    Ok(snapshot)
+}
+
+struct Bundle<'b> {
+    refs: Vec<(git2::Oid, String)>,
+    pack: &'b [u8],
+}
+
+fn parse_bundle<'b>(repo: &git2::Repository, bundle: &'b [u8]) -> eyre::Result<Bundle<'b>> {
+    let mut remaining = if let Some(rest) = bundle.strip_prefix(BUNDLE_V2_SIGNATURE) {
+        rest
+    } else if let Some(rest) = bundle.strip_prefix(BUNDLE_V3_SIGNATURE) {
+        rest
+    } else {
+        eyre::bail!("not a git bundle");
+    };
+
+    let mut refs = Vec::new();
+    loop {
+        let end = remaining
+            .find_byte(b'\n')
+            .ok_or_else(|| eyre::eyre!("truncated bundle header"))?;
+        let line = remaining[..end].to_str()?;
+        remaining = &remaining[end + 1..];
+        if line.is_empty() {
+            break;
+        } else if line.starts_with('@') {
+            // v3 capability
+            continue;
+        } else if let Some(prerequisite) = line.strip_prefix('-') {
+            let id = prerequisite.split(' ').next().unwrap_or_default();
+            let id = git2::Oid::from_str(id)?;
+            if repo.find_commit(id).is_err() {
+                eyre::bail!("bundle requires missing commit {}", id);
+            }
+        } else {
+            let (id, name) = line
+                .split_once(' ')
+                .ok_or_else(|| eyre::eyre!("invalid bundle reference {:?}", line))?;
+            refs.push((git2::Oid::from_str(id)?, name.to_owned()));
+        }
+    }
+
+    Ok(Bundle {
+        refs,
+        pack: remaining,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{git_commit, snapshot, with_message, with_parent};
+
+    #[test]
+    fn round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        let source = git2::Repository::init(root.path().join("source")).unwrap();
+        let base_id = git_commit(&source, None, ("base", "base"), "base");
+        let feature_id = git_commit(&source, Some(base_id), ("feature", "feature"), "feature");
+        let exported = with_message(
+            with_parent(
+                snapshot(&[("main", base_id), ("feature", feature_id)]),
+                "feature",
+                "main",
+            ),
+            "before rebase",
+        );
+        let bundle = root.path().join("snapshot.bundle");
+        export(&source, &exported, &bundle).unwrap();
+
+        let target = git2::Repository::init(root.path().join("target")).unwrap();
+        assert!(target.find_commit(feature_id).is_err());
+        let imported = import(&target, &bundle).unwrap();
+        assert_eq!(imported, exported);
+        let feature = target.find_commit(feature_id).unwrap();
+        assert_eq!(feature.parent_id(0).unwrap(), base_id);
+        assert_eq!(
+            feature.tree_id(),
+            source.find_commit(feature_id).unwrap().tree_id()
+        );
+
+        // Importing again finds everything already there
+        assert_eq!(import(&target, &bundle).unwrap(), exported);
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let root = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(root.path().join("repo")).unwrap();
+        let missing = snapshot(&[("main", crate::testing::oid(1))]);
+        let bundle = root.path().join("snapshot.bundle");
+        assert!(export(&repo, &missing, &bundle).is_err());
+        assert!(!bundle.exists());
+
+        std::fs::write(&bundle, "not a bundle").unwrap();
+        assert_eq!(
+            import(&repo, &bundle).unwrap_err().to_string(),
+            "not a git bundle"
+        );
+    }
+}
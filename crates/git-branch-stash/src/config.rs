@@ -2,14 +2,51 @@
 pub struct RepoConfig {
     pub protected_branches: Option<Vec<String>>,
     pub capacity: Option<usize>,
+    pub pins_count: Option<bool>,
+    pub keep_all_hours: Option<u64>,
+    pub keep_hourly: Option<u64>,
+    pub keep_daily: Option<u64>,
+    pub keep_weekly: Option<u64>,
+    pub branches: Option<Vec<String>>,
+    pub auto_capture: Option<bool>,
+    pub trash_days: Option<u64>,
+    pub store: Option<StoreLocation>,
+    pub store_id: Option<String>,
+    pub per_worktree: Option<bool>,
+    pub stacks: std::collections::BTreeMap<String, StackConfig>,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct StackConfig {
+    pub capacity: Option<usize>,
+    pub pins_count: Option<bool>,
+    pub keep_all_hours: Option<u64>,
+    pub keep_hourly: Option<u64>,
+    pub keep_daily: Option<u64>,
+    pub keep_weekly: Option<u64>,
+    pub branches: Option<Vec<String>>,
+    pub auto_capture: Option<bool>,
+    pub trash_days: Option<u64>,
 }
 
 static STACK_FIELD: &str = "stack.stack";
 static PROTECTED_STACK_FIELD: &str = "stack.protected-branch";
 static BACKUP_CAPACITY_FIELD: &str = "branch-stash.capacity";
+static PINS_COUNT_FIELD: &str = "branch-stash.pins-count";
+static KEEP_ALL_HOURS_FIELD: &str = "branch-stash.keep-all-hours";
+static KEEP_HOURLY_FIELD: &str = "branch-stash.keep-hourly";
+static KEEP_DAILY_FIELD: &str = "branch-stash.keep-daily";
+static KEEP_WEEKLY_FIELD: &str = "branch-stash.keep-weekly";
+static BRANCHES_FIELD: &str = "branch-stash.branches";
+static AUTO_CAPTURE_FIELD: &str = "branch-stash.auto-capture";
+static TRASH_DAYS_FIELD: &str = "branch-stash.trash-days";
+static STORE_FIELD: &str = "branch-stash.store";
+static STORE_ID_FIELD: &str = "branch-stash.store-id";
+static PER_WORKTREE_FIELD: &str = "branch-stash.per-worktree";
 
 static DEFAULT_PROTECTED_BRANCHES: [&str; 4] = ["main", "master", "dev", "stable"];
 const DEFAULT_CAPACITY: usize = 30;
+const DEFAULT_TRASH_DAYS: u64 = 14;
 
 impl RepoConfig {
     pub fn from_all(repo: &git2::Repository) -> eyre::Result<Self> {
@@ -34,18 +71,23 @@ impl RepoConfig {
     }
 
     pub fn from_repo(repo: &git2::Repository) -> eyre::Result<Self> {
-        let config_path = git_dir_config(repo);
+        let config = Self::from_path(&git_dir_config(repo));
+        let config = config.update(Self::from_path(&worktree_config(repo)));
+        Ok(config)
+    }
+
+    fn from_path(config_path: &std::path::Path) -> Self {
         log::trace!("Loading {}", config_path.display());
         if config_path.exists() {
-            match git2::Config::open(&config_path) {
-                Ok(config) => Ok(Self::from_gitconfig(&config)),
+            match git2::Config::open(config_path) {
+                Ok(config) => Self::from_gitconfig(&config),
                 Err(err) => {
                     log::debug!("Failed to load git config: {}", err);
-                    Ok(Default::default())
+                    Default::default()
                 }
             }
         } else {
-            Ok(Default::default())
+            Default::default()
         }
     }
 
@@ -98,6 +140,43 @@ impl RepoConfig {
                 }
             } else if key == BACKUP_CAPACITY_FIELD {
                 config.capacity = value.as_deref().and_then(|s| s.parse::<usize>().ok());
+            } else if key == PINS_COUNT_FIELD {
+                config.pins_count = value.as_deref().map(parse_bool).unwrap_or(Some(true));
+            } else if key == KEEP_ALL_HOURS_FIELD {
+                config.keep_all_hours = value.as_deref().and_then(|s| s.parse().ok());
+            } else if key == KEEP_HOURLY_FIELD {
+                config.keep_hourly = value.as_deref().and_then(|s| s.parse().ok());
+            } else if key == KEEP_DAILY_FIELD {
+                config.keep_daily = value.as_deref().and_then(|s| s.parse().ok());
+            } else if key == KEEP_WEEKLY_FIELD {
+                config.keep_weekly = value.as_deref().and_then(|s| s.parse().ok());
+            } else if key == BRANCHES_FIELD {
+                if let Some(value) = value {
+                    config
+                        .branches
+                        .get_or_insert_with(Vec::new)
+                        .push(value.into_owned());
+                }
+            } else if key == AUTO_CAPTURE_FIELD {
+                config.auto_capture = value.as_deref().map(parse_bool).unwrap_or(Some(true));
+            } else if key == TRASH_DAYS_FIELD {
+                config.trash_days = value.as_deref().and_then(|s| s.parse().ok());
+            } else if key == STORE_FIELD {
+                config.store = value.as_deref().and_then(|s| s.parse().ok());
+            } else if key == STORE_ID_FIELD {
+                config.store_id = value.map(|v| v.into_owned());
+            } else if key == PER_WORKTREE_FIELD {
+                // A key without a value is true, like in gitconfig
+                config.per_worktree = value.as_deref().map(parse_bool).unwrap_or(Some(true));
+            } else if let Some((stack, field)) = stack_field(&key) {
+                let stack = config.stacks.entry(stack.to_owned()).or_default();
+                if !stack.set(field, value.as_deref()) {
+                    log::warn!(
+                        "Unsupported config: {}={}",
+                        key,
+                        value.as_deref().unwrap_or("")
+                    );
+                }
             } else {
                 log::warn!(
                     "Unsupported config: {}={}",
@@ -123,8 +202,10 @@ impl RepoConfig {
     }
 
     fn from_defaults_internal(config: Option<&git2::Config>) -> Self {
-        let mut conf = Self::default();
-        conf.capacity = Some(DEFAULT_CAPACITY);
+        let mut conf = Self {
+            capacity: Some(DEFAULT_CAPACITY),
+            ..Default::default()
+        };
 
         let mut protected_branches: Vec<String> = Vec::new();
 
@@ -163,9 +244,70 @@ impl RepoConfig {
             .map(|i| i as usize)
             .ok();
 
+        let pins_count = config.get_bool(PINS_COUNT_FIELD).ok();
+
+        let keep_all_hours = get_u64(config, KEEP_ALL_HOURS_FIELD);
+        let keep_hourly = get_u64(config, KEEP_HOURLY_FIELD);
+        let keep_daily = get_u64(config, KEEP_DAILY_FIELD);
+        let keep_weekly = get_u64(config, KEEP_WEEKLY_FIELD);
+
+        let branches = config
+            .multivar(BRANCHES_FIELD, None)
+            .ok()
+            .map(|entries| {
+                let entries_ref = &entries;
+                entries_ref
+                    .flat_map(|e| e.into_iter())
+                    .filter_map(|e| e.value().map(|v| v.to_owned()))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|b| !b.is_empty());
+
+        let auto_capture = config.get_bool(AUTO_CAPTURE_FIELD).ok();
+
+        let trash_days = get_u64(config, TRASH_DAYS_FIELD);
+
+        let mut stacks = std::collections::BTreeMap::<String, StackConfig>::new();
+        if let Ok(entries) = config.entries(Some(r"^branch-stash\..+\..+$")) {
+            let entries_ref = &entries;
+            for entry in entries_ref.flat_map(|e| e.into_iter()) {
+                let key = match entry.name() {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if let Some((stack, field)) = stack_field(key) {
+                    let stack = stacks.entry(stack.to_owned()).or_default();
+                    if !stack.set(field, entry.value()) {
+                        log::warn!("Unsupported config: {}", key);
+                    }
+                }
+            }
+        }
+
+        let store = config
+            .get_string(STORE_FIELD)
+            .ok()
+            .and_then(|s| s.parse().ok());
+
+        let store_id = config.get_string(STORE_ID_FIELD).ok();
+
+        let per_worktree = config.get_bool(PER_WORKTREE_FIELD).ok();
+
         Self {
             protected_branches,
             capacity,
+            pins_count,
+            keep_all_hours,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            branches,
+            auto_capture,
+            trash_days,
+            store,
+            store_id,
+            per_worktree,
+            stacks,
         }
     }
 
@@ -186,6 +328,12 @@ impl RepoConfig {
                 config.set_multivar(PROTECTED_STACK_FIELD, "^$", branch)?;
             }
         }
+        if let Some(store) = self.store {
+            config.set_str(STORE_FIELD, store.as_str())?;
+        }
+        if let Some(store_id) = self.store_id.as_deref() {
+            config.set_str(STORE_ID_FIELD, store_id)?;
+        }
         Ok(())
     }
 
@@ -196,10 +344,41 @@ impl RepoConfig {
             (_, _) => (),
         }
         self.capacity = other.capacity.or(self.capacity);
+        self.pins_count = other.pins_count.or(self.pins_count);
+        self.keep_all_hours = other.keep_all_hours.or(self.keep_all_hours);
+        self.keep_hourly = other.keep_hourly.or(self.keep_hourly);
+        self.keep_daily = other.keep_daily.or(self.keep_daily);
+        self.keep_weekly = other.keep_weekly.or(self.keep_weekly);
+        self.branches = other.branches.or(self.branches);
+        self.auto_capture = other.auto_capture.or(self.auto_capture);
+        self.trash_days = other.trash_days.or(self.trash_days);
+        self.store = other.store.or(self.store);
+        self.store_id = other.store_id.or(self.store_id);
+        self.per_worktree = other.per_worktree.or(self.per_worktree);
+        for (name, stack) in other.stacks {
+            let existing = self.stacks.remove(&name).unwrap_or_default();
+            self.stacks.insert(name, existing.update(stack));
+        }
 
         self
     }
 
+    pub fn for_stack(&self, name: &str) -> Self {
+        let mut config = self.clone();
+        if let Some(stack) = self.stacks.get(name).cloned() {
+            config.capacity = stack.capacity.or(config.capacity);
+            config.pins_count = stack.pins_count.or(config.pins_count);
+            config.keep_all_hours = stack.keep_all_hours.or(config.keep_all_hours);
+            config.keep_hourly = stack.keep_hourly.or(config.keep_hourly);
+            config.keep_daily = stack.keep_daily.or(config.keep_daily);
+            config.keep_weekly = stack.keep_weekly.or(config.keep_weekly);
+            config.branches = stack.branches.or(config.branches);
+            config.auto_capture = stack.auto_capture.or(config.auto_capture);
+            config.trash_days = stack.trash_days.or(config.trash_days);
+        }
+        config
+    }
+
     pub fn protected_branches(&self) -> &[String] {
         self.protected_branches.as_deref().unwrap_or(&[])
     }
@@ -208,6 +387,45 @@ impl RepoConfig {
         let capacity = self.capacity.unwrap_or(DEFAULT_CAPACITY);
         (capacity != 0).then(|| capacity)
     }
+
+    pub fn pins_count(&self) -> bool {
+        self.pins_count.unwrap_or(false)
+    }
+
+    pub fn retention(&self) -> crate::Retention {
+        crate::Retention {
+            all_hours: self.keep_all_hours,
+            hourly: self.keep_hourly,
+            daily: self.keep_daily,
+            weekly: self.keep_weekly,
+        }
+    }
+
+    pub fn branches(&self) -> Option<&[String]> {
+        self.branches.as_deref()
+    }
+
+    pub fn auto_capture(&self) -> bool {
+        self.auto_capture.unwrap_or(false)
+    }
+
+    pub fn trash_days(&self) -> u64 {
+        self.trash_days.unwrap_or(DEFAULT_TRASH_DAYS)
+    }
+
+    pub fn trash_expiry(&self) -> std::time::Duration {
+        self.trash_days()
+            .checked_mul(24 * 60 * 60)
+            .map_or(std::time::Duration::MAX, std::time::Duration::from_secs)
+    }
+
+    pub fn store(&self) -> StoreLocation {
+        self.store.unwrap_or_default()
+    }
+
+    pub fn per_worktree(&self) -> bool {
+        self.per_worktree.unwrap_or(false)
+    }
 }
 
 impl std::fmt::Display for RepoConfig {
@@ -222,20 +440,255 @@ impl std::fmt::Display for RepoConfig {
             )?;
         }
         writeln!(f, "[{}]", BACKUP_CAPACITY_FIELD.split_once('.').unwrap().0)?;
+        self.fmt_stack(f)?;
+        writeln!(
+            f,
+            "\t{}={}",
+            STORE_FIELD.split_once('.').unwrap().1,
+            self.store()
+        )?;
+        writeln!(
+            f,
+            "\t{}={}",
+            PER_WORKTREE_FIELD.split_once('.').unwrap().1,
+            self.per_worktree()
+        )?;
+        if let Some(store_id) = self.store_id.as_deref() {
+            writeln!(
+                f,
+                "\t{}={}",
+                STORE_ID_FIELD.split_once('.').unwrap().1,
+                store_id
+            )?;
+        }
+        for name in self.stacks.keys() {
+            writeln!(
+                f,
+                "[{} \"{}\"]",
+                BACKUP_CAPACITY_FIELD.split_once('.').unwrap().0,
+                name
+            )?;
+            self.for_stack(name).fmt_stack(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl RepoConfig {
+    fn fmt_stack(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(
             f,
             "\t{}={}",
             BACKUP_CAPACITY_FIELD.split_once('.').unwrap().1,
             self.capacity().unwrap_or(0)
         )?;
+        writeln!(
+            f,
+            "\t{}={}",
+            PINS_COUNT_FIELD.split_once('.').unwrap().1,
+            self.pins_count()
+        )?;
+        for (field, value) in [
+            (KEEP_ALL_HOURS_FIELD, self.keep_all_hours),
+            (KEEP_HOURLY_FIELD, self.keep_hourly),
+            (KEEP_DAILY_FIELD, self.keep_daily),
+            (KEEP_WEEKLY_FIELD, self.keep_weekly),
+        ] {
+            if let Some(value) = value {
+                writeln!(f, "\t{}={}", field.split_once('.').unwrap().1, value)?;
+            }
+        }
+        for branch in self.branches().unwrap_or(&[]) {
+            writeln!(
+                f,
+                "\t{}={}",
+                BRANCHES_FIELD.split_once('.').unwrap().1,
+                branch
+            )?;
+        }
+        writeln!(
+            f,
+            "\t{}={}",
+            AUTO_CAPTURE_FIELD.split_once('.').unwrap().1,
+            self.auto_capture()
+        )?;
+        writeln!(
+            f,
+            "\t{}={}",
+            TRASH_DAYS_FIELD.split_once('.').unwrap().1,
+            self.trash_days()
+        )?;
         Ok(())
     }
 }
 
+impl StackConfig {
+    fn set(&mut self, field: &str, value: Option<&str>) -> bool {
+        // A key without a value is true, like in gitconfig
+        let as_bool = || value.map(parse_bool).unwrap_or(Some(true));
+        match field {
+            "capacity" => self.capacity = value.and_then(|s| s.parse().ok()),
+            "pins-count" => self.pins_count = as_bool(),
+            "keep-all-hours" => self.keep_all_hours = value.and_then(|s| s.parse().ok()),
+            "keep-hourly" => self.keep_hourly = value.and_then(|s| s.parse().ok()),
+            "keep-daily" => self.keep_daily = value.and_then(|s| s.parse().ok()),
+            "keep-weekly" => self.keep_weekly = value.and_then(|s| s.parse().ok()),
+            "branches" => {
+                if let Some(value) = value {
+                    self.branches
+                        .get_or_insert_with(Vec::new)
+                        .push(value.to_owned());
+                }
+            }
+            "auto-capture" => self.auto_capture = as_bool(),
+            "trash-days" => self.trash_days = value.and_then(|s| s.parse().ok()),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn update(mut self, other: Self) -> Self {
+        self.capacity = other.capacity.or(self.capacity);
+        self.pins_count = other.pins_count.or(self.pins_count);
+        self.keep_all_hours = other.keep_all_hours.or(self.keep_all_hours);
+        self.keep_hourly = other.keep_hourly.or(self.keep_hourly);
+        self.keep_daily = other.keep_daily.or(self.keep_daily);
+        self.keep_weekly = other.keep_weekly.or(self.keep_weekly);
+        self.branches = other.branches.or(self.branches);
+        self.auto_capture = other.auto_capture.or(self.auto_capture);
+        self.trash_days = other.trash_days.or(self.trash_days);
+        self
+    }
+}
+
+// `branch-stash.<stack>.<field>`
+fn stack_field(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix("branch-stash.")?;
+    rest.rsplit_once('.')
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StoreLocation {
+    Repo,
+    Global,
+}
+
+impl Default for StoreLocation {
+    fn default() -> Self {
+        Self::Repo
+    }
+}
+
+impl StoreLocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Repo => "repo",
+            Self::Global => "global",
+        }
+    }
+}
+
+impl std::str::FromStr for StoreLocation {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repo" => Ok(Self::Repo),
+            "global" => Ok(Self::Global),
+            _ => Err(eyre::eyre!("unsupported store location `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for StoreLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+// `get_i64` would let `-1` wrap around to a huge count
+fn get_u64(config: &git2::Config, field: &str) -> Option<u64> {
+    let value = config.get_i64(field).ok()?;
+    match std::convert::TryFrom::try_from(value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            log::warn!("Ignoring {}={}, it can't be negative", field, value);
+            None
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
 fn git_dir_config(repo: &git2::Repository) -> std::path::PathBuf {
-    repo.path().join("config")
+    crate::store::common_dir(repo).join("config")
+}
+
+fn worktree_config(repo: &git2::Repository) -> std::path::PathBuf {
+    repo.path().join("config.worktree")
 }
 
 fn default_branch(config: &git2::Config) -> &str {
     config.get_str("init.defaultBranch").ok().unwrap_or("main")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stack_overrides() {
+        let config = RepoConfig::from_env_iter(
+            vec![
+                ("branch-stash.capacity", Some("10")),
+                ("branch-stash.keep-daily", Some("7")),
+                ("branch-stash.milestones.capacity", Some("0")),
+                ("branch-stash.milestones.branches", Some("release/*")),
+                ("branch-stash.wip.auto-capture", None),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.map(|v| v.into()))),
+        );
+
+        let milestones = config.for_stack("milestones");
+        assert_eq!(milestones.capacity(), None);
+        assert_eq!(milestones.retention().daily, Some(7));
+        assert_eq!(milestones.branches(), Some(&["release/*".to_owned()][..]));
+        assert!(!milestones.auto_capture());
+
+        let wip = config.for_stack("wip");
+        assert_eq!(wip.capacity(), Some(10));
+        assert!(wip.auto_capture());
+
+        let recent = config.for_stack("recent");
+        assert_eq!(recent.capacity(), Some(10));
+        assert_eq!(recent.branches(), None);
+    }
+
+    #[test]
+    fn negative_trash_days() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("config");
+        let mut config = git2::Config::open(&path).unwrap();
+        config.set_i64(TRASH_DAYS_FIELD, -1).unwrap();
+
+        let config = RepoConfig::from_gitconfig(&config);
+        assert_eq!(config.trash_days, None);
+        assert_eq!(
+            config.trash_expiry(),
+            std::time::Duration::from_secs(DEFAULT_TRASH_DAYS * 24 * 60 * 60)
+        );
+
+        let config = RepoConfig {
+            trash_days: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert_eq!(config.trash_expiry(), std::time::Duration::MAX);
+    }
+}
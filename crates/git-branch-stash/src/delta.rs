@@ -0,0 +1,212 @@
+use crate::Branch;
+use crate::Snapshot;
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotDelta {
+    pub version: u32,
+    // Always `KIND`, so a snapshot with a foreign `base` field isn't mistaken for a delta
+    pub kind: String,
+    pub base: usize,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<Branch>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub moved: Vec<Branch>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub metadata: std::collections::BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_metadata: Vec<String>,
+    #[serde(flatten)]
+    pub extra: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+impl SnapshotDelta {
+    pub const KIND: &'static str = "delta";
+    const FIELDS: &'static [&'static str] = &[
+        "version",
+        "kind",
+        "base",
+        "added",
+        "removed",
+        "moved",
+        "metadata",
+        "removed_metadata",
+    ];
+
+    // Unknown snapshot fields are kept alongside the delta's own, so they must not clash
+    pub fn can_encode(snapshot: &Snapshot) -> bool {
+        !snapshot
+            .extra
+            .keys()
+            .any(|key| Self::FIELDS.contains(&key.as_str()))
+    }
+
+    pub fn new(base_index: usize, base: &Snapshot, snapshot: &Snapshot) -> Self {
+        let base_branches: std::collections::BTreeMap<_, _> =
+            base.branches.iter().map(|b| (b.name.as_str(), b)).collect();
+        let branches: std::collections::BTreeSet<_> =
+            snapshot.branches.iter().map(|b| b.name.as_str()).collect();
+
+        let mut added = Vec::new();
+        let mut moved = Vec::new();
+        for branch in snapshot.branches.iter() {
+            match base_branches.get(branch.name.as_str()) {
+                None => added.push(branch.clone()),
+                Some(base_branch) if *base_branch != branch => moved.push(branch.clone()),
+                Some(_) => {}
+            }
+        }
+        let removed = base_branches
+            .keys()
+            .filter(|name| !branches.contains(*name))
+            .map(|name| (*name).to_owned())
+            .collect();
+
+        let metadata = snapshot
+            .metadata
+            .iter()
+            .filter(|(key, value)| base.metadata.get(*key) != Some(*value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let removed_metadata = base
+            .metadata
+            .keys()
+            .filter(|key| !snapshot.metadata.contains_key(*key))
+            .cloned()
+            .collect();
+
+        Self {
+            version: Snapshot::VERSION,
+            kind: Self::KIND.to_owned(),
+            base: base_index,
+            added,
+            removed,
+            moved,
+            metadata,
+            removed_metadata,
+            extra: snapshot.extra.clone(),
+        }
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<Self, std::io::Error> {
+        let data = std::fs::read(path)?;
+        let value = serde_json::from_slice(&data)?;
+        Self::from_value(value)
+    }
+
+    pub fn from_value(value: serde_json::Value) -> Result<Self, std::io::Error> {
+        if !is_delta(&value) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not a snapshot delta",
+            ));
+        }
+        crate::snapshot::check_version(&value)?;
+        let b = serde_json::from_value(value)?;
+        Ok(b)
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let s = serde_json::to_string_pretty(self)?;
+        crate::fs::write_atomic(path, s.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn base_path(&self, path: &std::path::Path) -> std::path::PathBuf {
+        path.with_file_name(format!("{}.{}", self.base, crate::Stack::EXT))
+    }
+
+    pub fn apply(&self, base: &Snapshot) -> Snapshot {
+        let mut branches: std::collections::BTreeMap<_, _> = base
+            .branches
+            .iter()
+            .map(|b| (b.name.clone(), b.clone()))
+            .collect();
+        for name in self.removed.iter() {
+            branches.remove(name);
+        }
+        for branch in self.added.iter().chain(self.moved.iter()) {
+            branches.insert(branch.name.clone(), branch.clone());
+        }
+        let mut branches: Vec<_> = branches.into_values().collect();
+        branches.sort_unstable();
+
+        let mut metadata = base.metadata.clone();
+        for key in self.removed_metadata.iter() {
+            metadata.remove(key);
+        }
+        metadata.extend(self.metadata.clone());
+
+        Snapshot {
+            version: Snapshot::VERSION,
+            branches,
+            metadata,
+            extra: self.extra.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.added.len() + self.removed.len() + self.moved.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub fn is_delta(value: &serde_json::Value) -> bool {
+    value.get("kind").and_then(|k| k.as_str()) == Some(SnapshotDelta::KIND)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{branch, oid, snapshot};
+
+    #[test]
+    fn round_trip() {
+        let base = snapshot(&[("a", oid(1)), ("b", oid(2)), ("c", oid(3))]);
+        let mut target = snapshot(&[("a", oid(1)), ("c", oid(4)), ("d", oid(5))]);
+        target.insert_message("moved c");
+
+        let delta = SnapshotDelta::new(0, &base, &target);
+        assert_eq!(delta.added, vec![branch("d", oid(5))]);
+        assert_eq!(delta.removed, vec!["b".to_owned()]);
+        assert_eq!(delta.moved, vec![branch("c", oid(4))]);
+        assert_eq!(delta.apply(&base), target);
+    }
+
+    #[test]
+    fn removed_metadata() {
+        let mut base = snapshot(&[("a", oid(1))]);
+        base.insert_message("base");
+        let target = snapshot(&[("a", oid(1))]);
+
+        let delta = SnapshotDelta::new(0, &base, &target);
+        assert!(delta.is_empty());
+        assert_eq!(delta.removed_metadata, vec!["message".to_owned()]);
+        assert_eq!(delta.apply(&base), target);
+    }
+
+    #[test]
+    fn marked_by_kind() {
+        let base = snapshot(&[("a", oid(1)), ("b", oid(2))]);
+        let target = snapshot(&[("a", oid(1)), ("b", oid(3))]);
+        let delta = SnapshotDelta::new(0, &base, &target);
+        assert!(is_delta(&serde_json::to_value(&delta).unwrap()));
+
+        // Other tools may add their own fields
+        let mut foreign = target;
+        foreign
+            .extra
+            .insert("base".to_owned(), serde_json::Value::from(0));
+        assert!(!is_delta(&serde_json::to_value(&foreign).unwrap()));
+        assert!(!SnapshotDelta::can_encode(&foreign));
+    }
+}
@@ -0,0 +1,687 @@
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct SnapshotDiff {
+    pub changes: Vec<BranchChange>,
+    // Only filled in by `SnapshotDiff::with_commits`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commits: Vec<BranchCommits>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum BranchChange {
+    Created {
+        name: String,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+    },
+    Deleted {
+        name: String,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+    },
+    Moved {
+        name: String,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        from: git2::Oid,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        to: git2::Oid,
+        // `None` when either commit is missing
+        ahead: Option<usize>,
+        behind: Option<usize>,
+    },
+    Renamed {
+        from: String,
+        to: String,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+    },
+    Reparented {
+        name: String,
+        from: Option<String>,
+        to: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct BranchCommits {
+    pub name: String,
+    pub changes: Vec<CommitChange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum CommitChange {
+    Added {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+        summary: String,
+    },
+    Dropped {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+        summary: String,
+    },
+    // Same summary, different commit, like after a rebase or amend
+    Rewritten {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        from: git2::Oid,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        to: git2::Oid,
+        summary: String,
+    },
+}
+
+impl BranchChange {
+    // The name the branch has after the change
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Created { name, .. }
+            | Self::Deleted { name, .. }
+            | Self::Moved { name, .. }
+            | Self::Reparented { name, .. } => name,
+            Self::Renamed { to, .. } => to,
+        }
+    }
+}
+
+impl SnapshotDiff {
+    pub fn new(repo: &dyn crate::git::Repo, old: &crate::Snapshot, new: &crate::Snapshot) -> Self {
+        let old_branches: std::collections::BTreeMap<_, _> = old
+            .branches
+            .iter()
+            .map(|b| (b.name.as_str(), b.id))
+            .collect();
+        let new_branches: std::collections::BTreeMap<_, _> = new
+            .branches
+            .iter()
+            .map(|b| (b.name.as_str(), b.id))
+            .collect();
+
+        let mut changes = Vec::new();
+        for old_branch in old.branches.iter() {
+            let new_branch = match new.branches.iter().find(|b| b.name == old_branch.name) {
+                Some(new_branch) => new_branch,
+                None => continue,
+            };
+            let (from, to) = (parent(old_branch), parent(new_branch));
+            if from != to {
+                changes.push(BranchChange::Reparented {
+                    name: old_branch.name.clone(),
+                    from: from.map(|p| p.to_owned()),
+                    to: to.map(|p| p.to_owned()),
+                });
+            }
+        }
+        let mut created: Vec<_> = new_branches
+            .iter()
+            .filter(|(name, _)| !old_branches.contains_key(*name))
+            .collect();
+        for (name, id) in old_branches.iter() {
+            match new_branches.get(name) {
+                Some(new_id) if new_id == id => {}
+                Some(new_id) => {
+                    let (ahead, behind) = match repo.ahead_behind(*new_id, *id) {
+                        Some((ahead, behind)) => (Some(ahead), Some(behind)),
+                        None => (None, None),
+                    };
+                    changes.push(BranchChange::Moved {
+                        name: (*name).to_owned(),
+                        from: *id,
+                        to: *new_id,
+                        ahead,
+                        behind,
+                    });
+                }
+                None => match created.iter().position(|(_, new_id)| *new_id == id) {
+                    Some(i) => {
+                        let (new_name, _) = created.remove(i);
+                        changes.push(BranchChange::Renamed {
+                            from: (*name).to_owned(),
+                            to: (*new_name).to_owned(),
+                            id: *id,
+                        });
+                    }
+                    None => changes.push(BranchChange::Deleted {
+                        name: (*name).to_owned(),
+                        id: *id,
+                    }),
+                },
+            }
+        }
+        for (name, id) in created {
+            changes.push(BranchChange::Created {
+                name: (*name).to_owned(),
+                id: *id,
+            });
+        }
+        changes.sort_by(|a, b| a.name().cmp(b.name()));
+
+        Self {
+            changes,
+            commits: Vec::new(),
+        }
+    }
+
+    // Summarize what happened to the commits of each moved branch
+    pub fn with_commits(
+        mut self,
+        repo: &dyn crate::git::Repo,
+        old: &crate::Snapshot,
+        new: &crate::Snapshot,
+    ) -> Self {
+        for change in self.changes.iter() {
+            let (name, from, to) = match change {
+                BranchChange::Moved { name, from, to, .. } => (name, *from, *to),
+                _ => continue,
+            };
+            let base = repo.merge_base(from, to);
+            let old_commits = own_commits(repo, old, name, base);
+            let new_commits = own_commits(repo, new, name, base);
+
+            let mut changes = Vec::new();
+            let mut dropped: Vec<_> = old_commits
+                .iter()
+                .filter(|c| !new_commits.iter().any(|n| n.id == c.id))
+                .collect();
+            for commit in new_commits.iter().rev() {
+                if old_commits.iter().any(|o| o.id == commit.id) {
+                    continue;
+                }
+                let summary = commit.summary.to_string();
+                // The same patch is a rebase, the same summary is the change being reworked
+                let patch_id = repo.patch_id(commit.id);
+                let rewritten = dropped
+                    .iter()
+                    .position(|d| patch_id.is_some() && repo.patch_id(d.id) == patch_id)
+                    .or_else(|| dropped.iter().position(|d| d.summary == commit.summary));
+                match rewritten {
+                    Some(i) => {
+                        let original = dropped.remove(i);
+                        changes.push(CommitChange::Rewritten {
+                            from: original.id,
+                            to: commit.id,
+                            summary,
+                        });
+                    }
+                    None => changes.push(CommitChange::Added {
+                        id: commit.id,
+                        summary,
+                    }),
+                }
+            }
+            for commit in dropped.into_iter().rev() {
+                changes.push(CommitChange::Dropped {
+                    id: commit.id,
+                    summary: commit.summary.to_string(),
+                });
+            }
+            if !changes.is_empty() {
+                self.commits.push(BranchCommits {
+                    name: name.clone(),
+                    changes,
+                });
+            }
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct RangeDiff {
+    pub name: String,
+    // Newest first, removed commits after the ones still on the branch
+    pub changes: Vec<RangeChange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum RangeChange {
+    // Same commit, or the same patch applied elsewhere
+    Unchanged {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        from: git2::Oid,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        to: git2::Oid,
+        summary: String,
+    },
+    // Different patch, paired with an old commit only by having the same summary
+    Modified {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        from: git2::Oid,
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        to: git2::Oid,
+        summary: String,
+    },
+    Added {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+        summary: String,
+    },
+    Removed {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+        summary: String,
+    },
+    // Gone from the branch because its base already has an equivalent commit
+    Upstream {
+        #[serde(serialize_with = "crate::snapshot::serialize_oid")]
+        id: git2::Oid,
+        summary: String,
+    },
+}
+
+impl RangeDiff {
+    // Compares `name` in `old` against `new`, each from its recorded parent.  When either side
+    // has no usable parent, both sides are measured from where the old and new commits meet.
+    pub fn new(
+        repo: &dyn crate::git::Repo,
+        name: &str,
+        old: &crate::Snapshot,
+        new: &crate::Snapshot,
+    ) -> eyre::Result<Self> {
+        let range = |snapshot: &crate::Snapshot, side: &str| {
+            let branch = snapshot
+                .branches
+                .iter()
+                .find(|b| b.name == name)
+                .ok_or_else(|| eyre::eyre!("branch `{}` is not in the {} snapshot", name, side))?;
+            let range = snapshot.branch_commits(repo, branch).ok_or_else(|| {
+                eyre::eyre!("commit {} for `{}` is missing", branch.id, branch.name)
+            })?;
+            Ok::<_, eyre::Report>((branch.id, range))
+        };
+        let (old_tip, old_range) = range(old, "old")?;
+        let (new_tip, new_range) = range(new, "new")?;
+        let (old_commits, new_commits) = if old_range.base.is_some() && new_range.base.is_some() {
+            (old_range.commits, new_range.commits)
+        } else {
+            let base = repo.merge_base(old_tip, new_tip).ok_or_else(|| {
+                eyre::eyre!(
+                    "`{}` has no recorded parent and its old and new commits share no history",
+                    name
+                )
+            })?;
+            (
+                repo.commits_since(old_tip, base).collect::<Vec<_>>(),
+                repo.commits_since(new_tip, base).collect::<Vec<_>>(),
+            )
+        };
+
+        let old_patches: Vec<_> = old_commits.iter().map(|c| repo.patch_id(c.id)).collect();
+        let mut unmatched: Vec<_> = (0..old_commits.len()).collect();
+        let mut changes = Vec::new();
+        for commit in new_commits.iter() {
+            let summary = commit.summary.to_string();
+            let patch_id = repo.patch_id(commit.id);
+            let same = unmatched.iter().position(|i| {
+                old_commits[*i].id == commit.id
+                    || (patch_id.is_some() && old_patches[*i] == patch_id)
+            });
+            if let Some(position) = same {
+                let original = &old_commits[unmatched.remove(position)];
+                changes.push(RangeChange::Unchanged {
+                    from: original.id,
+                    to: commit.id,
+                    summary,
+                });
+                continue;
+            }
+            // Without a patch match, a commit with the same summary is taken to be the same
+            // change reworked, so an unrelated commit reusing a summary also shows as modified
+            let similar = unmatched
+                .iter()
+                .position(|i| old_commits[*i].summary == commit.summary);
+            match similar {
+                Some(position) => {
+                    let original = &old_commits[unmatched.remove(position)];
+                    changes.push(RangeChange::Modified {
+                        from: original.id,
+                        to: commit.id,
+                        summary,
+                    });
+                }
+                None => changes.push(RangeChange::Added {
+                    id: commit.id,
+                    summary,
+                }),
+            }
+        }
+        for i in unmatched {
+            let commit = &old_commits[i];
+            let summary = commit.summary.to_string();
+            if repo.contains_commit(new_tip, commit.id).unwrap_or(false) {
+                changes.push(RangeChange::Upstream {
+                    id: commit.id,
+                    summary,
+                });
+            } else {
+                changes.push(RangeChange::Removed {
+                    id: commit.id,
+                    summary,
+                });
+            }
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            changes,
+        })
+    }
+}
+
+fn parent(branch: &crate::Branch) -> Option<&str> {
+    match branch.metadata.get("parent") {
+        // Protected branches can be recorded as their own parent
+        Some(serde_json::Value::String(parent)) if parent != &branch.name => Some(parent),
+        _ => None,
+    }
+}
+
+// The branch's own commits, newest first: since its parent when known, otherwise since `base`
+fn own_commits(
+    repo: &dyn crate::git::Repo,
+    snapshot: &crate::Snapshot,
+    name: &str,
+    base: Option<git2::Oid>,
+) -> Vec<std::rc::Rc<crate::git::Commit>> {
+    let branch = match snapshot.branches.iter().find(|b| b.name == name) {
+        Some(branch) => branch,
+        None => return Vec::new(),
+    };
+    if parent(branch).is_some() {
+        match snapshot.branch_commits(repo, branch) {
+            Some(range) if range.base.is_some() => return range.commits,
+            _ => {}
+        }
+    }
+    match (repo.find_commit(branch.id), base) {
+        (None, _) => Vec::new(),
+        (Some(_), Some(base)) => repo.commits_since(branch.id, base).collect(),
+        // Unrelated histories, there is nothing to measure from
+        (Some(tip), None) => vec![tip],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{commit, git_commit, git_merge, oid, snapshot, with_parent};
+
+    #[test]
+    fn classify() {
+        let mut repo = crate::git::InMemoryRepo::new();
+        let base = commit(&mut repo, None, "commit");
+        let old_tip = commit(&mut repo, Some(base), "commit");
+        let new_first = commit(&mut repo, Some(base), "commit");
+        let new_second = commit(&mut repo, Some(new_first), "commit");
+        let missing = oid(999);
+
+        let old = snapshot(&[
+            ("feature", old_tip),
+            ("gone", base),
+            ("old-name", new_first),
+            ("lost", missing),
+        ]);
+        let new = snapshot(&[
+            ("feature", new_second),
+            ("new-name", new_first),
+            ("lost", base),
+            ("fresh", old_tip),
+        ]);
+        let diff = SnapshotDiff::new(&repo, &old, &new);
+        assert_eq!(
+            diff.changes,
+            [
+                BranchChange::Moved {
+                    name: "feature".to_owned(),
+                    from: old_tip,
+                    to: new_second,
+                    ahead: Some(2),
+                    behind: Some(1),
+                },
+                BranchChange::Created {
+                    name: "fresh".to_owned(),
+                    id: old_tip,
+                },
+                BranchChange::Deleted {
+                    name: "gone".to_owned(),
+                    id: base,
+                },
+                BranchChange::Moved {
+                    name: "lost".to_owned(),
+                    from: missing,
+                    to: base,
+                    ahead: None,
+                    behind: None,
+                },
+                BranchChange::Renamed {
+                    from: "old-name".to_owned(),
+                    to: "new-name".to_owned(),
+                    id: new_first,
+                },
+            ]
+        );
+        assert!(SnapshotDiff::new(&repo, &old, &old).is_empty());
+    }
+
+    #[test]
+    fn reparent_and_rewrite() {
+        let mut repo = crate::git::InMemoryRepo::new();
+        let base = commit(&mut repo, None, "base");
+        let a = commit(&mut repo, Some(base), "a");
+        let b = commit(&mut repo, Some(a), "b");
+        let release = commit(&mut repo, Some(base), "release");
+        let rewritten_a = commit(&mut repo, Some(release), "a");
+        let c = commit(&mut repo, Some(rewritten_a), "c");
+
+        let old = with_parent(
+            snapshot(&[("main", base), ("feature", b)]),
+            "feature",
+            "main",
+        );
+        let new = with_parent(
+            snapshot(&[("main", base), ("release", release), ("feature", c)]),
+            "feature",
+            "release",
+        );
+        let diff = SnapshotDiff::new(&repo, &old, &new).with_commits(&repo, &old, &new);
+        assert_eq!(
+            diff.changes,
+            [
+                BranchChange::Reparented {
+                    name: "feature".to_owned(),
+                    from: Some("main".to_owned()),
+                    to: Some("release".to_owned()),
+                },
+                BranchChange::Moved {
+                    name: "feature".to_owned(),
+                    from: b,
+                    to: c,
+                    ahead: Some(3),
+                    behind: Some(2),
+                },
+                BranchChange::Created {
+                    name: "release".to_owned(),
+                    id: release,
+                },
+            ]
+        );
+        assert_eq!(
+            diff.commits,
+            [BranchCommits {
+                name: "feature".to_owned(),
+                changes: vec![
+                    CommitChange::Rewritten {
+                        from: a,
+                        to: rewritten_a,
+                        summary: "a".to_owned(),
+                    },
+                    CommitChange::Added {
+                        id: c,
+                        summary: "c".to_owned(),
+                    },
+                    CommitChange::Dropped {
+                        id: b,
+                        summary: "b".to_owned(),
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn rewritten_by_patch() {
+        let root = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(root.path()).unwrap();
+        let base = git_commit(&raw, None, ("base", "base"), "base");
+        let fix = git_commit(&raw, Some(base), ("fix", "fix"), "fix bug");
+        let follow_up = git_commit(&raw, Some(fix), ("more", "more"), "fix the bug");
+        let release = git_commit(&raw, Some(base), ("release", "release"), "release");
+        let reworded = git_commit(&raw, Some(release), ("fix", "fix"), "fix the bug");
+        let repo = crate::git::GitRepo::new(raw);
+
+        let old = snapshot(&[("feature", follow_up)]);
+        let new = snapshot(&[("feature", reworded)]);
+        let diff = SnapshotDiff::new(&repo, &old, &new).with_commits(&repo, &old, &new);
+        assert_eq!(
+            diff.commits,
+            [BranchCommits {
+                name: "feature".to_owned(),
+                changes: vec![
+                    CommitChange::Added {
+                        id: release,
+                        summary: "release".to_owned(),
+                    },
+                    CommitChange::Rewritten {
+                        from: fix,
+                        to: reworded,
+                        summary: "fix the bug".to_owned(),
+                    },
+                    CommitChange::Dropped {
+                        id: follow_up,
+                        summary: "fix the bug".to_owned(),
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn moved_through_merges() {
+        let root = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(root.path()).unwrap();
+        let base = git_commit(&raw, None, ("base", "base"), "base");
+        let side = git_commit(&raw, Some(base), ("side", "side"), "side");
+        let main = git_commit(&raw, Some(base), ("main", "main"), "main");
+        let merge = git_merge(&raw, &[main, side], "merge");
+        let unrelated = git_commit(&raw, None, ("other", "other"), "other");
+        let repo = crate::git::GitRepo::new(raw);
+
+        let old = snapshot(&[("merged", main), ("replaced", main)]);
+        let new = snapshot(&[("merged", merge), ("replaced", unrelated)]);
+        let diff = SnapshotDiff::new(&repo, &old, &new);
+        assert_eq!(
+            diff.changes,
+            [
+                BranchChange::Moved {
+                    name: "merged".to_owned(),
+                    from: main,
+                    to: merge,
+                    ahead: Some(2),
+                    behind: Some(0),
+                },
+                BranchChange::Moved {
+                    name: "replaced".to_owned(),
+                    from: main,
+                    to: unrelated,
+                    ahead: Some(1),
+                    behind: Some(2),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn range_diff() {
+        let root = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(root.path()).unwrap();
+        let base = git_commit(&raw, None, ("base", "base"), "base");
+        let one = git_commit(&raw, Some(base), ("one", "one"), "one");
+        let two = git_commit(&raw, Some(one), ("two", "two"), "two");
+        let three = git_commit(&raw, Some(two), ("three", "three"), "three");
+        let main = git_commit(&raw, Some(base), ("three", "three"), "landed three");
+        let new_one = git_commit(&raw, Some(main), ("one", "one"), "one, reworded");
+        let new_two = git_commit(&raw, Some(new_one), ("two", "changed"), "two");
+        let four = git_commit(&raw, Some(new_two), ("four", "four"), "four");
+        let repo = crate::git::GitRepo::new(raw);
+
+        let old = with_parent(
+            snapshot(&[("main", base), ("feature", three)]),
+            "feature",
+            "main",
+        );
+        let new = with_parent(
+            snapshot(&[("main", main), ("feature", four)]),
+            "feature",
+            "main",
+        );
+        let diff = RangeDiff::new(&repo, "feature", &old, &new).unwrap();
+        assert_eq!(
+            diff.changes,
+            [
+                RangeChange::Added {
+                    id: four,
+                    summary: "four".to_owned(),
+                },
+                RangeChange::Modified {
+                    from: two,
+                    to: new_two,
+                    summary: "two".to_owned(),
+                },
+                RangeChange::Unchanged {
+                    from: one,
+                    to: new_one,
+                    summary: "one, reworded".to_owned(),
+                },
+                RangeChange::Upstream {
+                    id: three,
+                    summary: "three".to_owned(),
+                },
+            ]
+        );
+        assert!(RangeDiff::new(&repo, "missing", &old, &new).is_err());
+
+        // Without a parent, both sides are measured from where they meet
+        let old = snapshot(&[("feature", three)]);
+        let new = snapshot(&[("feature", four)]);
+        let diff = RangeDiff::new(&repo, "feature", &old, &new).unwrap();
+        assert_eq!(
+            diff.changes,
+            [
+                RangeChange::Added {
+                    id: four,
+                    summary: "four".to_owned(),
+                },
+                RangeChange::Modified {
+                    from: two,
+                    to: new_two,
+                    summary: "two".to_owned(),
+                },
+                RangeChange::Unchanged {
+                    from: one,
+                    to: new_one,
+                    summary: "one, reworded".to_owned(),
+                },
+                RangeChange::Unchanged {
+                    from: three,
+                    to: main,
+                    summary: "landed three".to_owned(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::Write;
+
+// Readers see either the old or the new content, never a partial write
+pub(crate) fn write_atomic(path: &std::path::Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let file_name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid path {}", path.display()),
+        )
+    })?;
+    // Must not end in `Stack::EXT` or a crash would leave it looking like a snapshot
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result?;
+    sync_dir(path);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &std::path::Path) {
+    // Make the rename itself durable
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = std::fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &std::path::Path) {}
+
+// Held while a stack is modified, like git's `<ref>.lock` files
+#[derive(Debug)]
+pub(crate) struct Lock {
+    path: std::path::PathBuf,
+}
+
+impl Lock {
+    const RETRIES: usize = 20;
+    const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
+    pub(crate) fn acquire(path: std::path::PathBuf) -> Result<Self, std::io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut retries = Self::RETRIES;
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    let _ = write!(file, "{}", std::process::id());
+                    log::trace!("Locked {}", path.display());
+                    return Ok(Self { path });
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && 0 < retries => {
+                    retries -= 1;
+                    std::thread::sleep(Self::RETRY_DELAY);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let owner = std::fs::read_to_string(&path)
+                        .ok()
+                        .filter(|pid| !pid.is_empty())
+                        .map(|pid| format!(" (process {})", pid))
+                        .unwrap_or_default();
+                    return Err(std::io::Error::new(
+                        err.kind(),
+                        format!(
+                            "{} is held by another git-branch-stash{}; if it is no longer running, remove the lock file",
+                            path.display(),
+                            owner
+                        ),
+                    ));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::debug!("Failed to unlock {}: {}", self.path.display(), err);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub stack: String,
+    pub path: std::path::PathBuf,
+    pub kind: ProblemKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    Corrupt(String),
+    MissingCommit { branch: String, id: git2::Oid },
+    UnknownParent { branch: String, parent: String },
+    IndexGap { previous: usize },
+    Stray,
+}
+
+impl Problem {
+    // Dropping snapshots leaves gaps and `branch-stash.branches` can filter out parents
+    pub fn is_error(&self) -> bool {
+        match self.kind {
+            ProblemKind::Corrupt(_) | ProblemKind::MissingCommit { .. } | ProblemKind::Stray => {
+                true
+            }
+            ProblemKind::UnknownParent { .. } | ProblemKind::IndexGap { .. } => false,
+        }
+    }
+
+    pub fn is_repairable(&self) -> bool {
+        match self.kind {
+            ProblemKind::Corrupt(_) | ProblemKind::Stray | ProblemKind::IndexGap { .. } => true,
+            ProblemKind::MissingCommit { .. } | ProblemKind::UnknownParent { .. } => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.path.display())?;
+        match &self.kind {
+            ProblemKind::Corrupt(err) => write!(f, "corrupt snapshot, {}", err),
+            ProblemKind::MissingCommit { branch, id } => {
+                write!(f, "branch `{}` points to missing commit {}", branch, id)
+            }
+            ProblemKind::UnknownParent { branch, parent } => write!(
+                f,
+                "branch `{}` has parent `{}` which is not in the snapshot",
+                branch, parent
+            ),
+            ProblemKind::IndexGap { previous } => {
+                write!(f, "follows {}, leaving a gap in `{}`", previous, self.stack)
+            }
+            ProblemKind::Stray => write!(f, "not a snapshot"),
+        }
+    }
+}
+
+pub fn check(store: &crate::Store, repo: &dyn crate::git::Repo) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for stack in store.stacks() {
+        check_stack(&stack, repo, &mut problems);
+    }
+    problems
+}
+
+fn check_stack(stack: &crate::Stack, repo: &dyn crate::git::Repo, problems: &mut Vec<Problem>) {
+    let mut problem = |path: &std::path::Path, kind| {
+        problems.push(Problem {
+            stack: stack.name.clone(),
+            path: path.to_owned(),
+            kind,
+        })
+    };
+
+    let paths: Vec<_> = stack.iter().collect();
+    for entry in std::fs::read_dir(stack.root()).into_iter().flatten() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        // Directories are nested stacks
+        if entry.file_type().map_or(false, |t| t.is_file())
+            && !paths.contains(&entry.path())
+            && !is_bookkeeping(&entry.file_name().to_string_lossy())
+        {
+            problem(&entry.path(), ProblemKind::Stray);
+        }
+    }
+
+    let mut previous = None;
+    for path in paths.iter() {
+        let index = crate::stack::index(path).unwrap();
+        if let Some(previous) = previous {
+            if previous + 1 != index {
+                problem(path, ProblemKind::IndexGap { previous });
+            }
+        }
+        previous = Some(index);
+
+        let errors = match std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+        {
+            // The schema only describes the current format, `load` migrates older ones
+            Ok(value) if crate::format_version(&value).ok() == Some(crate::Snapshot::VERSION) => {
+                crate::schema::validate(&value)
+                    .into_iter()
+                    .map(|e| e.to_string())
+                    .collect()
+            }
+            Ok(_) => Vec::new(),
+            Err(err) => vec![err],
+        };
+        if let Some(err) = errors.into_iter().next() {
+            problem(path, ProblemKind::Corrupt(err));
+            continue;
+        }
+        let snapshot = match crate::Snapshot::load(path) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                problem(path, ProblemKind::Corrupt(err.to_string()));
+                continue;
+            }
+        };
+
+        for branch in snapshot.branches.iter() {
+            if repo.find_commit(branch.id).is_none() {
+                problem(
+                    path,
+                    ProblemKind::MissingCommit {
+                        branch: branch.name.clone(),
+                        id: branch.id,
+                    },
+                );
+            }
+            if let Some(serde_json::Value::String(parent)) = branch.metadata.get("parent") {
+                if !snapshot.branches.iter().any(|b| &b.name == parent) {
+                    problem(
+                        path,
+                        ProblemKind::UnknownParent {
+                            branch: branch.name.clone(),
+                            parent: parent.clone(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Files the stack keeps next to its snapshots, like the locks of nested stacks and
+// `fs::write_atomic` temporaries, which may belong to a write that's still going on
+fn is_bookkeeping(file_name: &str) -> bool {
+    file_name == crate::Stack::PINS
+        || file_name.ends_with(".lock")
+        || (file_name.starts_with('.') && file_name.ends_with(".tmp"))
+}
+
+// Moves unusable files aside and renumbers the stacks they were in
+pub fn repair(store: &crate::Store, problems: &[Problem]) -> eyre::Result<usize> {
+    let mut repaired = 0;
+    let mut renumber = std::collections::BTreeSet::new();
+    for problem in problems {
+        match problem.kind {
+            ProblemKind::Corrupt(_) | ProblemKind::Stray => {
+                let stack = store.stack(&problem.stack)?;
+                let _lock = stack.lock()?;
+                let destination = store.quarantine(&problem.stack, &problem.path)?;
+                log::trace!(
+                    "Quarantined {} as {}",
+                    problem.path.display(),
+                    destination.display()
+                );
+            }
+            ProblemKind::IndexGap { .. } => {}
+            ProblemKind::MissingCommit { .. } | ProblemKind::UnknownParent { .. } => continue,
+        }
+        renumber.insert(problem.stack.as_str());
+        repaired += 1;
+    }
+    for name in renumber {
+        let renumbered = store.stack(name)?.renumber()?;
+        if renumbered != 0 {
+            log::trace!("Renumbered {} snapshots in `{}`", renumbered, name);
+        }
+    }
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{commit, numbered, with_parent};
+
+    #[test]
+    fn check_and_repair() {
+        let mut repo = crate::git::InMemoryRepo::new();
+        let id = commit(&mut repo, None, "one");
+        let missing = repo.gen_id();
+
+        let root = tempfile::tempdir().unwrap();
+        let store = crate::Store::new(root.path());
+        let mut stack = store.stack("recent").unwrap();
+        stack.push(numbered(&[id])).unwrap();
+        stack.push(numbered(&[id, missing])).unwrap();
+        stack.push(numbered(&[missing])).unwrap();
+        stack
+            .push(with_parent(numbered(&[id]), "b0", "main"))
+            .unwrap();
+        std::fs::remove_file(root.path().join("recent/2.bak")).unwrap();
+        std::fs::write(root.path().join("recent/4.bak"), "{").unwrap();
+        std::fs::write(root.path().join("recent/notes.txt"), "").unwrap();
+
+        let problems = check(&store, &repo);
+        let mut kinds: Vec<_> = problems
+            .iter()
+            .map(|p| {
+                let file = p.path.file_name().unwrap().to_str().unwrap();
+                let kind = match p.kind {
+                    ProblemKind::Corrupt(_) => "corrupt",
+                    ProblemKind::MissingCommit { .. } => "commit",
+                    ProblemKind::UnknownParent { .. } => "parent",
+                    ProblemKind::IndexGap { .. } => "gap",
+                    ProblemKind::Stray => "stray",
+                };
+                (file.to_owned(), kind)
+            })
+            .collect();
+        kinds.sort();
+        let expected = [
+            ("1.bak".to_owned(), "commit"),
+            ("3.bak".to_owned(), "gap"),
+            ("3.bak".to_owned(), "parent"),
+            ("4.bak".to_owned(), "corrupt"),
+            ("notes.txt".to_owned(), "stray"),
+        ];
+        assert_eq!(kinds, expected);
+
+        assert_eq!(repair(&store, &problems).unwrap(), 3);
+        let paths: Vec<_> = stack.iter().collect();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[2], root.path().join("recent/2.bak"));
+        assert!(crate::Snapshot::load(&paths[2]).unwrap().branches[0]
+            .metadata
+            .contains_key("parent"));
+        let remaining: Vec<_> = check(&store, &repo)
+            .into_iter()
+            .filter(|p| p.is_repairable())
+            .collect();
+        assert_eq!(remaining, []);
+        assert!(root.path().join(".quarantine/recent/notes.txt").exists());
+    }
+
+    #[test]
+    fn nested_stacks() {
+        let mut repo = crate::git::InMemoryRepo::new();
+        let id = commit(&mut repo, None, "one");
+
+        let root = tempfile::tempdir().unwrap();
+        let store = crate::Store::new(root.path());
+        store.stack("team").unwrap().push(numbered(&[id])).unwrap();
+        let alice = store.stack("team/alice").unwrap();
+        let mut alice_writer = alice.clone();
+        alice_writer.push(numbered(&[id])).unwrap();
+        std::fs::write(root.path().join("team/.1.bak.tmp"), "").unwrap();
+
+        let _lock = alice.lock().unwrap();
+        assert!(root.path().join("team/alice.lock").exists());
+        let problems = check(&store, &repo);
+        assert_eq!(problems, []);
+        assert_eq!(repair(&store, &problems).unwrap(), 0);
+        assert!(root.path().join("team/alice.lock").exists());
+        assert!(alice_writer.push(numbered(&[id, id])).is_err());
+    }
+}
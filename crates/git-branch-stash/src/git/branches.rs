@@ -5,12 +5,10 @@ pub struct Branches {
 
 impl Branches {
     pub fn new(branches: impl IntoIterator<Item = crate::git::Branch>) -> Self {
-        let mut grouped_branches = std::collections::BTreeMap::new();
+        let mut grouped_branches: std::collections::BTreeMap<_, Vec<_>> =
+            std::collections::BTreeMap::new();
         for branch in branches {
-            grouped_branches
-                .entry(branch.id)
-                .or_insert_with(Vec::new)
-                .push(branch);
+            grouped_branches.entry(branch.id).or_default().push(branch);
         }
         Self {
             branches: grouped_branches,
@@ -28,10 +26,7 @@ impl Branches {
     }
 
     pub fn insert(&mut self, branch: crate::git::Branch) {
-        self.branches
-            .entry(branch.id)
-            .or_insert_with(Vec::new)
-            .push(branch);
+        self.branches.entry(branch.id).or_default().push(branch);
     }
 
     pub fn extend(&mut self, branches: impl Iterator<Item = crate::git::Branch>) {
@@ -6,6 +6,7 @@ pub trait Repo {
 
     fn is_dirty(&self) -> bool;
     fn merge_base(&self, one: git2::Oid, two: git2::Oid) -> Option<git2::Oid>;
+    fn ahead_behind(&self, local: git2::Oid, upstream: git2::Oid) -> Option<(usize, usize)>;
 
     fn find_commit(&self, id: git2::Oid) -> Option<std::rc::Rc<Commit>>;
     fn head_commit(&self) -> std::rc::Rc<Commit>;
@@ -15,15 +16,25 @@ pub trait Repo {
         &self,
         head_id: git2::Oid,
     ) -> Box<dyn Iterator<Item = std::rc::Rc<Commit>> + '_>;
+    fn commits_since(
+        &self,
+        head_id: git2::Oid,
+        base_id: git2::Oid,
+    ) -> Box<dyn Iterator<Item = std::rc::Rc<Commit>> + '_>;
     fn contains_commit(
         &self,
         haystack_id: git2::Oid,
         needle_id: git2::Oid,
     ) -> Result<bool, git2::Error>;
+    fn patch_id(&self, id: git2::Oid) -> Option<git2::Oid>;
+    fn lost_commits(&self, head_id: git2::Oid) -> Result<Vec<std::rc::Rc<Commit>>, git2::Error>;
 
     fn stash_push(&mut self, message: Option<&str>) -> Result<git2::Oid, git2::Error>;
     fn stash_pop(&mut self, stash_id: git2::Oid) -> Result<(), git2::Error>;
 
+    fn fetch_commits(&mut self, source: &str, ids: &[git2::Oid]) -> Result<(), git2::Error>;
+    fn recover_commits(&mut self, ids: &[git2::Oid]) -> Result<(), git2::Error>;
+
     fn branch(&mut self, name: &str, id: git2::Oid) -> Result<(), git2::Error>;
     fn delete_branch(&mut self, name: &str) -> Result<(), git2::Error>;
     fn find_local_branch(&self, name: &str) -> Option<Branch>;
@@ -92,6 +103,7 @@ pub struct GitRepo {
     repo: git2::Repository,
     push_remote: Option<String>,
     pull_remote: Option<String>,
+    offline: bool,
     commits: std::cell::RefCell<std::collections::HashMap<git2::Oid, std::rc::Rc<Commit>>>,
     interned_strings: std::cell::RefCell<std::collections::HashSet<std::rc::Rc<str>>>,
 }
@@ -102,6 +114,7 @@ impl GitRepo {
             repo,
             push_remote: None,
             pull_remote: None,
+            offline: false,
             commits: Default::default(),
             interned_strings: Default::default(),
         }
@@ -115,6 +128,10 @@ impl GitRepo {
         self.pull_remote = Some(remote.to_owned());
     }
 
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
     pub fn push_remote(&self) -> &str {
         self.push_remote.as_deref().unwrap_or("origin")
     }
@@ -162,6 +179,11 @@ impl GitRepo {
         self.repo.merge_base(one, two).ok()
     }
 
+    // Commits only reachable from `local` and commits only reachable from `upstream`
+    pub fn ahead_behind(&self, local: git2::Oid, upstream: git2::Oid) -> Option<(usize, usize)> {
+        self.repo.graph_ahead_behind(local, upstream).ok()
+    }
+
     pub fn find_commit(&self, id: git2::Oid) -> Option<std::rc::Rc<Commit>> {
         let mut commits = self.commits.borrow_mut();
         if let Some(commit) = commits.get(&id) {
@@ -247,6 +269,22 @@ impl GitRepo {
             .filter_map(move |oid| self.find_commit(oid))
     }
 
+    // Reachable from `head_id` but not from `base_id`
+    pub fn commits_since(
+        &self,
+        head_id: git2::Oid,
+        base_id: git2::Oid,
+    ) -> impl Iterator<Item = std::rc::Rc<Commit>> + '_ {
+        let mut revwalk = self.repo.revwalk().unwrap();
+        revwalk.push(head_id).unwrap();
+        revwalk.hide(base_id).unwrap();
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL).unwrap();
+
+        revwalk
+            .filter_map(Result::ok)
+            .filter_map(move |oid| self.find_commit(oid))
+    }
+
     pub fn contains_commit(
         &self,
         haystack_id: git2::Oid,
@@ -280,7 +318,11 @@ impl GitRepo {
                 return Ok(false);
             }
 
-            let sig = self.repo.signature().unwrap();
+            // The commit is thrown away, so any identity will do
+            let sig = match self.repo.signature() {
+                Ok(sig) => sig,
+                Err(_) => git2::Signature::now("git-branch-stash", "git-branch-stash@localhost")?,
+            };
             match rebase.commit(None, &sig, None).map_err(|e| {
                 let _ = rebase.abort();
                 e
@@ -303,6 +345,47 @@ impl GitRepo {
         }
     }
 
+    // Stable across rebases as long as the change itself is the same
+    pub fn patch_id(&self, id: git2::Oid) -> Option<git2::Oid> {
+        let commit = self.repo.find_commit(id).ok()?;
+        if commit.parent_count() > 1 {
+            return None;
+        }
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree().ok()?),
+            Err(_) => None,
+        };
+        let diff = self
+            .repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree().ok()?), None)
+            .ok()?;
+        // Every empty commit would share one
+        if diff.deltas().len() == 0 {
+            return None;
+        }
+        diff.patchid(None).ok()
+    }
+
+    // Reachable from `head_id` but from no local or remote-tracking branch
+    pub fn lost_commits(
+        &self,
+        head_id: git2::Oid,
+    ) -> Result<Vec<std::rc::Rc<Commit>>, git2::Error> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push(head_id)?;
+        revwalk.hide_glob("refs/heads/*")?;
+        revwalk.hide_glob("refs/remotes/*")?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+
+        let mut commits = Vec::new();
+        for id in revwalk {
+            if let Some(commit) = self.find_commit(id?) {
+                commits.push(commit);
+            }
+        }
+        Ok(commits)
+    }
+
     pub fn stash_push(&mut self, message: Option<&str>) -> Result<git2::Oid, git2::Error> {
         let signature = self.repo.signature()?;
         self.repo.stash_save2(&signature, message, None)
@@ -328,6 +411,127 @@ impl GitRepo {
         self.repo.stash_pop(index, None)
     }
 
+    pub fn fetch_commits(&mut self, source: &str, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        let remote_url = self
+            .repo
+            .find_remote(source)
+            .ok()
+            .and_then(|r| r.url().map(|u| u.to_owned()));
+        let url = remote_url.as_deref().unwrap_or(source);
+
+        if let Some(path) = local_path(url) {
+            self.copy_commits(&path, ids)
+        } else {
+            self.download_commits(url, ids)
+        }
+    }
+
+    fn copy_commits(
+        &mut self,
+        path: &std::path::Path,
+        ids: &[git2::Oid],
+    ) -> Result<(), git2::Error> {
+        log::trace!("Copying {} commits from {}", ids.len(), path.display());
+        let source_repo = git2::Repository::open(path)?;
+        let source_odb = source_repo.odb()?;
+        let mut walk = source_repo.revwalk()?;
+        let mut found = 0;
+        for id in ids {
+            if source_odb.exists(*id) {
+                walk.push(*id)?;
+                found += 1;
+            } else {
+                log::warn!("{} not found in {}", id, path.display());
+            }
+        }
+        if found == 0 {
+            return Ok(());
+        }
+        for branch in self.local_branches() {
+            if source_odb.exists(branch.id) {
+                walk.hide(branch.id)?;
+            }
+        }
+
+        let mut builder = source_repo.packbuilder()?;
+        builder.insert_walk(&mut walk)?;
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack)?;
+
+        let odb = self.repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        std::io::Write::write_all(&mut writer, &pack)
+            .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    fn download_commits(&mut self, url: &str, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        // `download` only writes objects, so the refspec destinations, remote-tracking branches,
+        // and FETCH_HEAD are left untouched
+        log::trace!("Fetching {} commits from {}", ids.len(), url);
+        let mut remote = self.repo.remote_anonymous(url)?;
+
+        // Ask for the commits themselves, like `git fetch <remote> <oid>`, which servers allow
+        // with `uploadpack.allowReachableSHA1InWant` or `uploadpack.allowAnySHA1InWant`
+        let refspecs: Vec<_> = ids
+            .iter()
+            .map(|id| format!("{}:refs/branch-stash/fetch/{}", id, id))
+            .collect();
+        if let Err(err) = download(&mut remote, &refspecs) {
+            log::warn!("Failed to fetch commits by id from {}: {}", url, err);
+        }
+
+        let missing = self.missing_commits(ids)?;
+        if !missing.is_empty() {
+            log::trace!("Fetching branches from {}", url);
+            download(
+                &mut remote,
+                &["+refs/heads/*:refs/branch-stash/fetch/*".to_owned()],
+            )?;
+            for id in self.missing_commits(&missing)? {
+                log::warn!("{} not found in any branch of {}", id, url);
+            }
+        }
+        Ok(())
+    }
+
+    fn missing_commits(&self, ids: &[git2::Oid]) -> Result<Vec<git2::Oid>, git2::Error> {
+        let odb = self.repo.odb()?;
+        Ok(ids.iter().copied().filter(|id| !odb.exists(*id)).collect())
+    }
+
+    pub fn recover_commits(&mut self, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        if self.offline {
+            log::debug!("Offline, not fetching {} missing commits", ids.len());
+            return Ok(());
+        }
+
+        let mut remotes = vec![self.pull_remote().to_owned()];
+        if self.push_remote() != self.pull_remote() {
+            remotes.push(self.push_remote().to_owned());
+        }
+        for remote in remotes {
+            let missing: Vec<_> = ids
+                .iter()
+                .copied()
+                .filter(|id| self.repo.find_commit(*id).is_err())
+                .collect();
+            if missing.is_empty() {
+                break;
+            }
+            if self.repo.find_remote(&remote).is_err() {
+                log::trace!("No remote {}, skipping", remote);
+                continue;
+            }
+            log::debug!("Fetching {} missing commits from {}", missing.len(), remote);
+            if let Err(err) = self.fetch_commits(&remote, &missing) {
+                log::warn!("Failed to fetch from {}: {}", remote, err);
+            }
+        }
+        Ok(())
+    }
+
     pub fn branch(&mut self, name: &str, id: git2::Oid) -> Result<(), git2::Error> {
         let commit = self.repo.find_commit(id)?;
         self.repo.branch(name, &commit, true)?;
@@ -472,6 +676,10 @@ impl Repo for GitRepo {
         self.merge_base(one, two)
     }
 
+    fn ahead_behind(&self, local: git2::Oid, upstream: git2::Oid) -> Option<(usize, usize)> {
+        self.ahead_behind(local, upstream)
+    }
+
     fn find_commit(&self, id: git2::Oid) -> Option<std::rc::Rc<Commit>> {
         self.find_commit(id)
     }
@@ -495,6 +703,14 @@ impl Repo for GitRepo {
         Box::new(self.commits_from(head_id))
     }
 
+    fn commits_since(
+        &self,
+        head_id: git2::Oid,
+        base_id: git2::Oid,
+    ) -> Box<dyn Iterator<Item = std::rc::Rc<Commit>> + '_> {
+        Box::new(self.commits_since(head_id, base_id))
+    }
+
     fn contains_commit(
         &self,
         haystack_id: git2::Oid,
@@ -503,6 +719,14 @@ impl Repo for GitRepo {
         self.contains_commit(haystack_id, needle_id)
     }
 
+    fn patch_id(&self, id: git2::Oid) -> Option<git2::Oid> {
+        self.patch_id(id)
+    }
+
+    fn lost_commits(&self, head_id: git2::Oid) -> Result<Vec<std::rc::Rc<Commit>>, git2::Error> {
+        self.lost_commits(head_id)
+    }
+
     fn stash_push(&mut self, message: Option<&str>) -> Result<git2::Oid, git2::Error> {
         self.stash_push(message)
     }
@@ -511,6 +735,14 @@ impl Repo for GitRepo {
         self.stash_pop(stash_id)
     }
 
+    fn fetch_commits(&mut self, source: &str, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        self.fetch_commits(source, ids)
+    }
+
+    fn recover_commits(&mut self, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        self.recover_commits(ids)
+    }
+
     fn branch(&mut self, name: &str, id: git2::Oid) -> Result<(), git2::Error> {
         self.branch(name, id)
     }
@@ -606,6 +838,22 @@ impl InMemoryRepo {
             .next()
     }
 
+    pub fn ahead_behind(&self, local: git2::Oid, upstream: git2::Oid) -> Option<(usize, usize)> {
+        self.find_commit(local)?;
+        self.find_commit(upstream)?;
+        let ancestors = |id| {
+            self.commits_from(id)
+                .map(|c| c.id)
+                .collect::<std::collections::HashSet<_>>()
+        };
+        let local = ancestors(local);
+        let upstream = ancestors(upstream);
+        Some((
+            local.difference(&upstream).count(),
+            upstream.difference(&local).count(),
+        ))
+    }
+
     pub fn find_commit(&self, id: git2::Oid) -> Option<std::rc::Rc<Commit>> {
         self.commits.get(&id).map(|c| c.1.clone())
     }
@@ -637,6 +885,16 @@ impl InMemoryRepo {
         }
     }
 
+    pub fn commits_since(
+        &self,
+        head_id: git2::Oid,
+        base_id: git2::Oid,
+    ) -> impl Iterator<Item = std::rc::Rc<Commit>> + '_ {
+        // History is linear, so everything past the first shared commit is shared too
+        self.commits_from(head_id)
+            .take_while(move |c| !self.contains_commit(base_id, c.id).unwrap_or(false))
+    }
+
     pub fn contains_commit(
         &self,
         haystack_id: git2::Oid,
@@ -653,6 +911,27 @@ impl InMemoryRepo {
         Ok(false)
     }
 
+    pub fn patch_id(&self, _id: git2::Oid) -> Option<git2::Oid> {
+        // No diffs to hash
+        None
+    }
+
+    pub fn lost_commits(
+        &self,
+        head_id: git2::Oid,
+    ) -> Result<Vec<std::rc::Rc<Commit>>, git2::Error> {
+        // No remotes to check
+        Ok(self
+            .commits_from(head_id)
+            .take_while(|commit| {
+                !self
+                    .branches
+                    .values()
+                    .any(|b| self.contains_commit(b.id, commit.id).unwrap_or(false))
+            })
+            .collect())
+    }
+
     pub fn stash_push(&mut self, _message: Option<&str>) -> Result<git2::Oid, git2::Error> {
         Err(git2::Error::new(
             git2::ErrorCode::NotFound,
@@ -669,6 +948,18 @@ impl InMemoryRepo {
         ))
     }
 
+    pub fn fetch_commits(&mut self, _source: &str, _ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        Err(git2::Error::new(
+            git2::ErrorCode::NotFound,
+            git2::ErrorClass::Reference,
+            "fetch is unsupported",
+        ))
+    }
+
+    pub fn recover_commits(&mut self, _ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        Ok(())
+    }
+
     pub fn branch(&mut self, name: &str, id: git2::Oid) -> Result<(), git2::Error> {
         self.branches.insert(
             name.to_owned(),
@@ -755,6 +1046,10 @@ impl Repo for InMemoryRepo {
         self.merge_base(one, two)
     }
 
+    fn ahead_behind(&self, local: git2::Oid, upstream: git2::Oid) -> Option<(usize, usize)> {
+        self.ahead_behind(local, upstream)
+    }
+
     fn find_commit(&self, id: git2::Oid) -> Option<std::rc::Rc<Commit>> {
         self.find_commit(id)
     }
@@ -774,6 +1069,14 @@ impl Repo for InMemoryRepo {
         Box::new(self.commits_from(head_id))
     }
 
+    fn commits_since(
+        &self,
+        head_id: git2::Oid,
+        base_id: git2::Oid,
+    ) -> Box<dyn Iterator<Item = std::rc::Rc<Commit>> + '_> {
+        Box::new(self.commits_since(head_id, base_id))
+    }
+
     fn contains_commit(
         &self,
         haystack_id: git2::Oid,
@@ -786,6 +1089,14 @@ impl Repo for InMemoryRepo {
         self.head_branch()
     }
 
+    fn patch_id(&self, id: git2::Oid) -> Option<git2::Oid> {
+        self.patch_id(id)
+    }
+
+    fn lost_commits(&self, head_id: git2::Oid) -> Result<Vec<std::rc::Rc<Commit>>, git2::Error> {
+        self.lost_commits(head_id)
+    }
+
     fn stash_push(&mut self, message: Option<&str>) -> Result<git2::Oid, git2::Error> {
         self.stash_push(message)
     }
@@ -794,6 +1105,14 @@ impl Repo for InMemoryRepo {
         self.stash_pop(stash_id)
     }
 
+    fn fetch_commits(&mut self, source: &str, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        self.fetch_commits(source, ids)
+    }
+
+    fn recover_commits(&mut self, ids: &[git2::Oid]) -> Result<(), git2::Error> {
+        self.recover_commits(ids)
+    }
+
     fn branch(&mut self, name: &str, id: git2::Oid) -> Result<(), git2::Error> {
         self.branch(name, id)
     }
@@ -819,6 +1138,23 @@ impl Repo for InMemoryRepo {
     }
 }
 
+fn download(remote: &mut git2::Remote<'_>, refspecs: &[String]) -> Result<(), git2::Error> {
+    let mut options = git2::FetchOptions::new();
+    options.download_tags(git2::AutotagOption::None);
+    let result = remote.download(refspecs, Some(&mut options));
+    remote.disconnect()?;
+    result
+}
+
+fn local_path(url: &str) -> Option<std::path::PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(std::path::PathBuf::from(path))
+    } else {
+        let path = std::path::Path::new(url);
+        path.exists().then(|| path.to_owned())
+    }
+}
+
 pub fn stash_push(repo: &mut dyn Repo, context: &str) -> Option<git2::Oid> {
     let branch = repo.head_branch();
     let stash_msg = format!(
@@ -854,3 +1190,73 @@ pub fn stash_pop(repo: &mut dyn Repo, stash_id: Option<git2::Oid>) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::git_commit;
+
+    fn fixture() -> (tempfile::TempDir, GitRepo, git2::Oid, git2::Oid) {
+        let root = tempfile::tempdir().unwrap();
+
+        let upstream = git2::Repository::init(root.path().join("upstream")).unwrap();
+        let feature_id = git_commit(&upstream, None, ("feature", "feature"), "feature");
+        let feature = upstream.find_commit(feature_id).unwrap();
+        upstream.branch("feature", &feature, false).unwrap();
+
+        let local = git2::Repository::init(root.path().join("local")).unwrap();
+        let tracked_id = git_commit(&local, None, ("local", "local"), "local");
+        local
+            .reference("refs/remotes/origin/feature", tracked_id, false, "test")
+            .unwrap();
+        let url = format!("file://{}", upstream.path().display());
+        local.remote("origin", &url).unwrap();
+
+        (root, GitRepo::new(local), feature_id, tracked_id)
+    }
+
+    #[test]
+    fn copy_from_path() {
+        let (root, mut repo, feature_id, _) = fixture();
+        let path = root.path().join("upstream");
+        repo.fetch_commits(path.to_str().unwrap(), &[feature_id])
+            .unwrap();
+        assert!(repo.repo.odb().unwrap().exists(feature_id));
+    }
+
+    #[test]
+    fn download_leaves_refs_alone() {
+        let (root, mut repo, feature_id, tracked_id) = fixture();
+        let url = format!("file://{}", root.path().join("upstream").display());
+        repo.download_commits(&url, &[feature_id]).unwrap();
+        assert!(repo.repo.odb().unwrap().exists(feature_id));
+        assert_eq!(
+            repo.repo
+                .refname_to_id("refs/remotes/origin/feature")
+                .unwrap(),
+            tracked_id
+        );
+        let refs: Vec<_> = repo
+            .repo
+            .references()
+            .unwrap()
+            .map(|r| r.unwrap().name().unwrap().to_owned())
+            .collect();
+        assert_eq!(refs, ["refs/remotes/origin/feature"]);
+        assert!(!repo.repo.path().join("FETCH_HEAD").exists());
+    }
+
+    #[test]
+    fn download_unreachable() {
+        // libgit2's local transport only packs what its branches reach, so this only exercises
+        // the fallback
+        let (root, mut repo, feature_id, _) = fixture();
+        let upstream = git2::Repository::open(root.path().join("upstream")).unwrap();
+        let orphan_id = git_commit(&upstream, Some(feature_id), ("orphan", "orphan"), "orphan");
+        let url = format!("file://{}", root.path().join("upstream").display());
+        repo.download_commits(&url, &[feature_id, orphan_id])
+            .unwrap();
+        assert!(repo.repo.odb().unwrap().exists(feature_id));
+        assert!(!repo.repo.odb().unwrap().exists(orphan_id));
+    }
+}
@@ -0,0 +1,170 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry<K> {
+    pub event: HistoryEvent,
+    // `None` while the branch is deleted
+    pub branch: Option<crate::Branch>,
+    // The first and last of the consecutive snapshots this entry covers
+    pub first: K,
+    pub last: K,
+    pub count: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HistoryEvent {
+    // Already present in the oldest snapshot
+    Recorded,
+    Created,
+    Moved,
+    Rewritten,
+    Reparented,
+    Deleted,
+}
+
+// Walks snapshots oldest first, yielding an entry each time `name` changes
+pub struct BranchHistory<'r, K, I> {
+    repo: &'r dyn crate::git::Repo,
+    name: String,
+    snapshots: I,
+    seen_absent: bool,
+    pending: Option<HistoryEntry<K>>,
+}
+
+impl<'r, K, I> BranchHistory<'r, K, I>
+where
+    K: Clone,
+    I: Iterator<Item = (K, crate::Snapshot)>,
+{
+    pub fn new(
+        repo: &'r dyn crate::git::Repo,
+        name: &str,
+        snapshots: impl IntoIterator<Item = (K, crate::Snapshot), IntoIter = I>,
+    ) -> Self {
+        Self {
+            repo,
+            name: name.to_owned(),
+            snapshots: snapshots.into_iter(),
+            seen_absent: false,
+            pending: None,
+        }
+    }
+}
+
+impl<'r, K, I> Iterator for BranchHistory<'r, K, I>
+where
+    K: Clone,
+    I: Iterator<Item = (K, crate::Snapshot)>,
+{
+    type Item = HistoryEntry<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, snapshot) in self.snapshots.by_ref() {
+            let name = self.name.as_str();
+            let branch = snapshot.branches.into_iter().find(|b| b.name == name);
+            let event = match self.pending.as_mut() {
+                None if branch.is_none() => {
+                    self.seen_absent = true;
+                    continue;
+                }
+                None if self.seen_absent => HistoryEvent::Created,
+                None => HistoryEvent::Recorded,
+                Some(pending) => {
+                    let event = match (&pending.branch, &branch) {
+                        (Some(old), Some(new)) if old.id == new.id => (old.metadata.get("parent")
+                            != new.metadata.get("parent"))
+                        .then(|| HistoryEvent::Reparented),
+                        (Some(old), Some(new)) => Some(moved(self.repo, old.id, new.id)),
+                        (Some(_), None) => Some(HistoryEvent::Deleted),
+                        (None, Some(_)) => Some(HistoryEvent::Created),
+                        (None, None) => None,
+                    };
+                    match event {
+                        Some(event) => event,
+                        None => {
+                            pending.last = key;
+                            pending.count += 1;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let entry = HistoryEntry {
+                event,
+                branch,
+                first: key.clone(),
+                last: key,
+                count: 1,
+            };
+            if let Some(previous) = self.pending.replace(entry) {
+                return Some(previous);
+            }
+        }
+        self.pending.take()
+    }
+}
+
+// Missing commits can't be compared, so assume the branch just moved
+fn moved(repo: &dyn crate::git::Repo, old: git2::Oid, new: git2::Oid) -> HistoryEvent {
+    if repo.find_commit(old).is_some()
+        && repo.find_commit(new).is_some()
+        && repo.merge_base(old, new) != Some(old)
+    {
+        HistoryEvent::Rewritten
+    } else {
+        HistoryEvent::Moved
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{commit, with_parent};
+
+    fn snapshot(branches: &[(&str, git2::Oid, &str)]) -> crate::Snapshot {
+        let ids: Vec<_> = branches.iter().map(|(name, id, _)| (*name, *id)).collect();
+        branches.iter().fold(
+            crate::testing::snapshot(&ids),
+            |snapshot, (name, _, parent)| with_parent(snapshot, name, parent),
+        )
+    }
+
+    #[test]
+    fn timeline() {
+        let mut repo = crate::git::InMemoryRepo::new();
+        let base = commit(&mut repo, None, "commit");
+        let one = commit(&mut repo, Some(base), "commit");
+        let two = commit(&mut repo, Some(one), "commit");
+        let rewritten = commit(&mut repo, Some(base), "commit");
+
+        let snapshots = vec![
+            snapshot(&[("main", base, "main")]),
+            snapshot(&[("main", base, "main"), ("feature", one, "main")]),
+            snapshot(&[("feature", one, "main")]),
+            snapshot(&[("feature", two, "main")]),
+            snapshot(&[("feature", two, "other")]),
+            snapshot(&[("feature", rewritten, "other")]),
+            snapshot(&[]),
+            snapshot(&[]),
+            snapshot(&[("feature", rewritten, "other")]),
+        ];
+        let history: Vec<_> =
+            BranchHistory::new(&repo, "feature", snapshots.into_iter().enumerate())
+                .map(|e| (e.event, e.branch.map(|b| b.id), e.first, e.last))
+                .collect();
+        let expected = [
+            (HistoryEvent::Created, Some(one), 1, 2),
+            (HistoryEvent::Moved, Some(two), 3, 3),
+            (HistoryEvent::Reparented, Some(two), 4, 4),
+            (HistoryEvent::Rewritten, Some(rewritten), 5, 5),
+            (HistoryEvent::Deleted, None, 6, 7),
+            (HistoryEvent::Created, Some(rewritten), 8, 8),
+        ];
+        assert_eq!(history, expected);
+
+        let snapshots = vec![(0, snapshot(&[("main", base, "main")]))];
+        let history: Vec<_> = BranchHistory::new(&repo, "main", snapshots)
+            .map(|e| e.event)
+            .collect();
+        assert_eq!(history, [HistoryEvent::Recorded]);
+    }
+}
@@ -1,10 +1,31 @@
 #![allow(clippy::collapsible_else_if)]
 
+pub mod bundle;
 pub mod config;
 pub mod git;
+pub mod schema;
 
+pub use delta::*;
+pub use diff::*;
+pub use fsck::*;
+pub use history::*;
+pub use lost::*;
+pub use reference::*;
+pub use retention::*;
 pub use snapshot::*;
 pub use stack::*;
+pub use store::*;
 
+mod delta;
+mod diff;
+mod fs;
+mod fsck;
+mod history;
+mod lost;
+mod reference;
+mod retention;
 mod snapshot;
 mod stack;
+mod store;
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,88 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LostBranch {
+    pub name: String,
+    pub id: git2::Oid,
+    pub commits: Vec<std::rc::Rc<crate::git::Commit>>,
+}
+
+// Branches in `snapshot` with commits that no current branch or remote-tracking branch reaches
+pub fn lost_branches(
+    repo: &dyn crate::git::Repo,
+    snapshot: &crate::Snapshot,
+) -> Result<Vec<LostBranch>, git2::Error> {
+    let mut lost = Vec::new();
+    for branch in snapshot.branches.iter() {
+        // Already gone, nothing to recover
+        if repo.find_commit(branch.id).is_none() {
+            continue;
+        }
+        let commits = repo.lost_commits(branch.id)?;
+        if !commits.is_empty() {
+            lost.push(LostBranch {
+                name: branch.name.clone(),
+                id: branch.id,
+                commits,
+            });
+        }
+    }
+
+    // Stacked branches share commits, credit each one to the nearest branch
+    let mut order: Vec<_> = (0..lost.len()).collect();
+    order.sort_by_key(|i| lost[*i].commits.len());
+    let mut seen = std::collections::HashSet::new();
+    for i in order {
+        lost[i].commits.retain(|c| seen.insert(c.id));
+    }
+    lost.retain(|b| !b.commits.is_empty());
+
+    Ok(lost)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{commit, oid, snapshot};
+
+    #[test]
+    fn lost_in_stack() {
+        let mut repo = crate::git::InMemoryRepo::new();
+        let base = commit(&mut repo, None, "commit");
+        let feature = commit(&mut repo, Some(base), "commit");
+        let stacked_one = commit(&mut repo, Some(feature), "commit");
+        let stacked_two = commit(&mut repo, Some(stacked_one), "commit");
+        let kept = commit(&mut repo, Some(base), "commit");
+        let missing = oid(999);
+        repo.mark_branch(crate::git::Branch {
+            name: "main".to_owned(),
+            id: base,
+            push_id: None,
+            pull_id: None,
+        });
+        repo.mark_branch(crate::git::Branch {
+            name: "kept".to_owned(),
+            id: kept,
+            push_id: None,
+            pull_id: None,
+        });
+
+        let branches = [
+            ("stacked", stacked_two),
+            ("feature", feature),
+            ("main", base),
+            ("kept", kept),
+            ("missing", missing),
+        ];
+        let snapshot = snapshot(&branches);
+
+        let lost: Vec<_> = lost_branches(&repo, &snapshot)
+            .unwrap()
+            .into_iter()
+            .map(|b| (b.name, b.commits.iter().map(|c| c.id).collect::<Vec<_>>()))
+            .collect();
+        let expected = [
+            ("stacked".to_owned(), vec![stacked_two, stacked_one]),
+            ("feature".to_owned(), vec![feature]),
+        ];
+        assert_eq!(lost, expected);
+    }
+}
@@ -0,0 +1,381 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotRef {
+    pub stack: String,
+    pub selector: Selector,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Selector {
+    Latest,
+    Index(usize),
+    Age(std::time::Duration),
+    Name(String),
+}
+
+impl SnapshotRef {
+    pub fn new(stack: &str) -> Self {
+        Self {
+            stack: stack.to_owned(),
+            selector: Selector::Latest,
+        }
+    }
+
+    pub fn resolve(&self, store: &crate::Store) -> eyre::Result<SnapshotHandle> {
+        let stack = store.stack(&self.stack)?;
+        let paths: Vec<_> = stack.iter().collect();
+        if paths.is_empty() {
+            eyre::bail!("stack `{}` has no snapshots", self.stack);
+        }
+
+        let path = match &self.selector {
+            Selector::Latest => paths.last().unwrap().clone(),
+            Selector::Index(n) => match paths.len().checked_sub(n + 1) {
+                Some(i) => paths[i].clone(),
+                None => eyre::bail!(
+                    "`{}` is out of range, stack `{}` has {} snapshots",
+                    self,
+                    self.stack,
+                    paths.len()
+                ),
+            },
+            Selector::Age(age) => {
+                let cutoff = std::time::SystemTime::now()
+                    .checked_sub(*age)
+                    .unwrap_or(std::time::UNIX_EPOCH);
+                let mut found = None;
+                for path in paths.iter().rev() {
+                    let snapshot = crate::Snapshot::load(path)?;
+                    let time = crate::stack::created(path, &snapshot)?;
+                    if time <= cutoff {
+                        found = Some(path.clone());
+                        break;
+                    }
+                }
+                found.ok_or_else(|| {
+                    eyre::eyre!(
+                        "`{}` is older than every snapshot in `{}`",
+                        self,
+                        self.stack
+                    )
+                })?
+            }
+            Selector::Name(name) => {
+                let mut named = Vec::new();
+                let mut messages = Vec::new();
+                for (i, path) in paths.iter().rev().enumerate() {
+                    let snapshot = crate::Snapshot::load(path)?;
+                    if snapshot.name() == Some(name.as_str()) {
+                        named.push((i, path));
+                    } else if snapshot.messages().contains(&name.as_str()) {
+                        messages.push((i, path));
+                    }
+                }
+                let matches = if named.is_empty() { messages } else { named };
+                match matches.as_slice() {
+                    [] => eyre::bail!("no snapshot named `{}` in stack `{}`", name, self.stack),
+                    [(_, path)] => (*path).clone(),
+                    _ => eyre::bail!(
+                        "`{}` is ambiguous, it matches {}",
+                        self,
+                        matches
+                            .iter()
+                            .map(|(i, _)| format!("{}@{{{}}}", self.stack, i))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+            }
+        };
+
+        let index = crate::stack::index(&path)
+            .ok_or_else(|| eyre::eyre!("invalid snapshot path {}", path.display()))?;
+        Ok(SnapshotHandle { stack, index, path })
+    }
+}
+
+impl std::str::FromStr for SnapshotRef {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stack, selector) = match s.strip_suffix('}').and_then(|s| s.rsplit_once("@{")) {
+            Some((stack, selector)) => (stack, parse_selector(selector)?),
+            None => (s, Selector::Latest),
+        };
+        let stack = if stack.is_empty() {
+            crate::Stack::DEFAULT_STACK
+        } else {
+            stack
+        };
+        crate::Stack::check_name(stack)?;
+        Ok(Self {
+            stack: stack.to_owned(),
+            selector,
+        })
+    }
+}
+
+impl std::fmt::Display for SnapshotRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.selector {
+            Selector::Latest => write!(f, "{}", self.stack),
+            Selector::Index(n) => write!(f, "{}@{{{}}}", self.stack, n),
+            Selector::Age(age) => {
+                let seconds = age.as_secs();
+                let (count, unit) = UNITS
+                    .iter()
+                    .rev()
+                    .find(|(_, unit)| seconds % unit == 0)
+                    .map(|(name, unit)| (seconds / unit, *name))
+                    .unwrap_or((seconds, "second"));
+                let plural = if count == 1 { "" } else { "s" };
+                write!(f, "{}@{{{}.{}{}.ago}}", self.stack, count, unit, plural)
+            }
+            Selector::Name(name) => write!(f, "{}@{{{}}}", self.stack, name),
+        }
+    }
+}
+
+pub fn check_name(name: &str) -> eyre::Result<()> {
+    if name.contains(|c| c == '{' || c == '}') {
+        eyre::bail!("snapshot name `{}` cannot contain braces", name);
+    }
+    match parse_selector(name)? {
+        Selector::Name(_) => Ok(()),
+        _ => eyre::bail!("snapshot name `{}` would be read as an index or age", name),
+    }
+}
+
+fn parse_selector(selector: &str) -> eyre::Result<Selector> {
+    if selector.is_empty() {
+        eyre::bail!("empty snapshot selector `@{{}}`");
+    }
+    if let Ok(n) = selector.parse::<usize>() {
+        return Ok(Selector::Index(n));
+    }
+    if let Some(age) = parse_age(selector) {
+        return Ok(Selector::Age(age));
+    }
+    Ok(Selector::Name(selector.to_owned()))
+}
+
+fn parse_age(age: &str) -> Option<std::time::Duration> {
+    if age == "yesterday" {
+        return Some(std::time::Duration::from_secs(DAY));
+    }
+    let parts: Vec<_> = age.split(|c| c == '.' || c == ' ').collect();
+    let (count, unit) = match parts.as_slice() {
+        [count, unit, "ago"] => (count.parse::<u64>().ok()?, *unit),
+        _ => return None,
+    };
+    let unit = unit.strip_suffix('s').unwrap_or(unit);
+    let (_, seconds) = UNITS.iter().find(|(name, _)| *name == unit)?;
+    Some(std::time::Duration::from_secs(count.checked_mul(*seconds)?))
+}
+
+const DAY: u64 = 24 * 60 * 60;
+const UNITS: &[(&str, u64)] = &[
+    ("second", 1),
+    ("minute", 60),
+    ("hour", 60 * 60),
+    ("day", DAY),
+    ("week", 7 * DAY),
+    ("month", 30 * DAY),
+    ("year", 365 * DAY),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotHandle {
+    pub stack: crate::Stack,
+    pub index: usize,
+    pub path: std::path::PathBuf,
+}
+
+impl SnapshotHandle {
+    pub fn load(&self) -> Result<crate::Snapshot, std::io::Error> {
+        crate::Snapshot::load(&self.path)
+    }
+
+    pub fn copy_to(&self, target: &mut crate::Stack) -> eyre::Result<std::path::PathBuf> {
+        if target.name == self.stack.name {
+            eyre::bail!("snapshot is already in `{}`", target.name);
+        }
+        let snapshot = self.load()?;
+        let path = target.append(snapshot)?;
+        Ok(path)
+    }
+
+    // Add `snapshot` to `target` without merging into or evicting this handle's snapshot
+    pub fn push_beside(
+        &self,
+        target: &mut crate::Stack,
+        snapshot: crate::Snapshot,
+    ) -> eyre::Result<std::path::PathBuf> {
+        let path = if target.name == self.stack.name {
+            target.append(snapshot)?
+        } else {
+            target.push(snapshot)?
+        };
+        Ok(path)
+    }
+
+    pub fn move_to(mut self, target: &mut crate::Stack) -> eyre::Result<std::path::PathBuf> {
+        let path = self.copy_to(target)?;
+        self.stack.forget_snapshot(&self.path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{oid, with_message};
+
+    #[test]
+    fn parse() {
+        let cases = [
+            ("recent", "recent", Selector::Latest),
+            ("wip@{3}", "wip", Selector::Index(3)),
+            ("@{0}", "recent", Selector::Index(0)),
+            (
+                "recent@{2.hours.ago}",
+                "recent",
+                Selector::Age(std::time::Duration::from_secs(2 * 60 * 60)),
+            ),
+            (
+                "recent@{1 week ago}",
+                "recent",
+                Selector::Age(std::time::Duration::from_secs(7 * DAY)),
+            ),
+            (
+                "wip@{before-rebase}",
+                "wip",
+                Selector::Name("before-rebase".to_owned()),
+            ),
+        ];
+        for (input, stack, selector) in cases {
+            let actual: SnapshotRef = input.parse().unwrap();
+            assert_eq!(actual.stack, stack, "{}", input);
+            assert_eq!(actual.selector, selector, "{}", input);
+        }
+        assert!("recent@{}".parse::<SnapshotRef>().is_err());
+    }
+
+    #[test]
+    fn names() {
+        check_name("before-big-rebase").unwrap();
+        assert!(check_name("").is_err());
+        assert!(check_name("3").is_err());
+        assert!(check_name("2.days.ago").is_err());
+        assert!(check_name("a}b").is_err());
+    }
+
+    fn snapshot(message: &str, time: u64) -> crate::Snapshot {
+        let mut snapshot = with_message(
+            crate::testing::snapshot(&[("main", oid(time as u32))]),
+            message,
+        );
+        snapshot.insert_time(std::time::UNIX_EPOCH + std::time::Duration::from_secs(time));
+        snapshot
+    }
+
+    #[test]
+    fn move_renumbers() {
+        let root = tempfile::tempdir().unwrap();
+        let store = crate::Store::new(root.path());
+        let mut recent = store.stack("recent").unwrap();
+        let mut keep = store.stack("keep").unwrap();
+        recent.push(snapshot("a", 1)).unwrap();
+        recent.push(snapshot("b", 2)).unwrap();
+        keep.push(snapshot("c", 3)).unwrap();
+
+        let handle = "recent@{a}"
+            .parse::<SnapshotRef>()
+            .unwrap()
+            .resolve(&store)
+            .unwrap();
+        let path = handle.move_to(&mut keep).unwrap();
+        assert_eq!(path, root.path().join("keep").join("1.bak"));
+        assert_eq!(recent.iter().count(), 1);
+        assert_eq!(
+            "keep@{0}"
+                .parse::<SnapshotRef>()
+                .unwrap()
+                .resolve(&store)
+                .unwrap()
+                .load()
+                .unwrap(),
+            snapshot("a", 1)
+        );
+
+        // Matching the latest snapshot's refs doesn't fold it into that snapshot
+        recent.push(snapshot("d", 3)).unwrap();
+        let handle = "recent@{d}"
+            .parse::<SnapshotRef>()
+            .unwrap()
+            .resolve(&store)
+            .unwrap();
+        let path = handle.move_to(&mut keep).unwrap();
+        assert_eq!(path, root.path().join("keep").join("2.bak"));
+        assert_eq!(keep.iter().count(), 3);
+        assert_eq!(recent.iter().count(), 1);
+    }
+
+    #[test]
+    fn push_beside() {
+        let root = tempfile::tempdir().unwrap();
+        let store = crate::Store::new(root.path());
+        let mut recent = store.stack("recent").unwrap();
+        recent.capacity(Some(2));
+        recent.push(snapshot("a", 1)).unwrap();
+        recent.push(snapshot("b", 2)).unwrap();
+
+        let handle = "recent@{1}"
+            .parse::<SnapshotRef>()
+            .unwrap()
+            .resolve(&store)
+            .unwrap();
+        handle.push_beside(&mut recent, snapshot("c", 2)).unwrap();
+        handle.push_beside(&mut recent, snapshot("d", 3)).unwrap();
+        assert_eq!(handle.load().unwrap(), snapshot("a", 1));
+        assert_eq!(recent.iter().count(), 4);
+
+        let mut other = store.stack("other").unwrap();
+        handle.push_beside(&mut other, snapshot("e", 4)).unwrap();
+        handle.push_beside(&mut other, snapshot("f", 4)).unwrap();
+        assert_eq!(other.iter().count(), 1);
+    }
+
+    #[test]
+    fn resolve() {
+        let root = tempfile::tempdir().unwrap();
+        let store = crate::Store::new(root.path());
+        let mut stack = store.stack("recent").unwrap();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        stack.push(snapshot("old", now - 3 * DAY)).unwrap();
+        stack.push(snapshot("dup", now - 2 * DAY)).unwrap();
+        stack.push(snapshot("dup", now - DAY)).unwrap();
+        stack.push(snapshot("new", now)).unwrap();
+
+        let resolve = |r: &str| r.parse::<SnapshotRef>().unwrap().resolve(&store);
+        assert_eq!(resolve("recent").unwrap().index, 3);
+        assert_eq!(resolve("recent@{0}").unwrap().index, 3);
+        assert_eq!(resolve("recent@{3}").unwrap().index, 0);
+        assert_eq!(resolve("recent@{36.hours.ago}").unwrap().index, 1);
+        assert_eq!(resolve("recent@{old}").unwrap().index, 0);
+        assert_eq!(
+            resolve("recent@{4}").unwrap_err().to_string(),
+            "`recent@{4}` is out of range, stack `recent` has 4 snapshots"
+        );
+        assert_eq!(
+            resolve("recent@{dup}").unwrap_err().to_string(),
+            "`recent@{dup}` is ambiguous, it matches recent@{1}, recent@{2}"
+        );
+        assert_eq!(
+            resolve("recent@{1.year.ago}").unwrap_err().to_string(),
+            "`recent@{1.year.ago}` is older than every snapshot in `recent`"
+        );
+    }
+}
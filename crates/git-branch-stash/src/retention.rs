@@ -0,0 +1,108 @@
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct Retention {
+    pub all_hours: Option<u64>,
+    pub hourly: Option<u64>,
+    pub daily: Option<u64>,
+    pub weekly: Option<u64>,
+}
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+impl Retention {
+    pub fn is_enabled(&self) -> bool {
+        self.all_hours.is_some()
+            || self.hourly.is_some()
+            || self.daily.is_some()
+            || self.weekly.is_some()
+    }
+
+    // `snapshots` is oldest first, like `Stack::iter`
+    pub fn expired<'s, T>(
+        &self,
+        snapshots: &'s [(T, std::time::SystemTime)],
+        now: std::time::SystemTime,
+    ) -> Vec<&'s T> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+
+        let now = seconds(now);
+        let rules = [
+            (self.all_hours, HOUR, None),
+            (self.hourly, HOUR, Some(HOUR)),
+            (self.daily, DAY, Some(DAY)),
+            (self.weekly, WEEK, Some(WEEK)),
+        ];
+        let mut seen: Vec<std::collections::HashSet<u64>> = vec![Default::default(); rules.len()];
+        let mut expired = Vec::new();
+        for (i, (snapshot, time)) in snapshots.iter().enumerate().rev() {
+            let time = seconds(*time);
+            let age = now.saturating_sub(time);
+            let latest = i == snapshots.len() - 1;
+            let mut keep = latest;
+            for ((count, unit, bucket), seen) in rules.iter().zip(seen.iter_mut()) {
+                let count = match count {
+                    Some(count) => *count,
+                    None => continue,
+                };
+                if count.saturating_mul(*unit) <= age {
+                    continue;
+                }
+                match bucket {
+                    // Newest first, so the first one seen in a bucket is kept
+                    Some(bucket) => keep |= seen.insert(time / bucket),
+                    None => keep = true,
+                }
+            }
+            if !keep {
+                expired.push(snapshot);
+            }
+        }
+        expired.reverse();
+        expired
+    }
+}
+
+fn seconds(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tiers() {
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(100 * DAY);
+        let at = |age: u64| now - std::time::Duration::from_secs(age);
+        let snapshots = [
+            ("two-weeks", at(14 * DAY)),
+            ("three-days-a", at(3 * DAY - HOUR)),
+            ("three-days-b", at(3 * DAY - 2 * HOUR)),
+            ("hours-a", at(5 * HOUR + 30 * 60)),
+            ("hours-b", at(5 * HOUR + 10 * 60)),
+            ("hours-c", at(4 * HOUR)),
+            ("minutes-a", at(20 * 60)),
+            ("minutes-b", at(10 * 60)),
+        ];
+        let retention = Retention {
+            all_hours: Some(1),
+            hourly: Some(24),
+            daily: Some(7),
+            weekly: None,
+        };
+        let expired = retention.expired(&snapshots, now);
+        assert_eq!(expired, vec![&"two-weeks", &"three-days-a", &"hours-a"]);
+    }
+
+    #[test]
+    fn disabled() {
+        let now = std::time::SystemTime::now();
+        let snapshots = [(0, std::time::UNIX_EPOCH), (1, now)];
+        assert!(Retention::default().expired(&snapshots, now).is_empty());
+    }
+}
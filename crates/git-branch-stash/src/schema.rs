@@ -0,0 +1,454 @@
+pub fn snapshot_schema() -> serde_json::Value {
+    serde_json::json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "$id": "https://github.com/gitext-rs/git-branch-stash/blob/main/crates/git-branch-stash/snapshot.schema.json",
+        "title": "git-branch-stash snapshot",
+        "description": "A snapshot of local branches, stored as `.git/branch-stash/<stack>/<index>.bak`",
+        "oneOf": [
+            { "$ref": "#/definitions/snapshot" },
+            { "$ref": "#/definitions/delta" },
+        ],
+        "definitions": {
+            "version": {
+                "description": "Format version, see `git branch-stash migrate`",
+                "type": "integer",
+                "minimum": 1,
+                "maximum": crate::Snapshot::VERSION,
+            },
+            "snapshot": {
+                "description": "A full snapshot, without `version` when written before snapshots were versioned",
+                "type": "object",
+                "required": ["branches"],
+                "properties": {
+                    "version": { "$ref": "#/definitions/version" },
+                    "branches": {
+                        "type": "array",
+                        "items": { "$ref": "#/definitions/branch" },
+                    },
+                    "metadata": { "$ref": "#/definitions/metadata" },
+                },
+                "additionalProperties": true,
+            },
+            "delta": {
+                "description": "Changes relative to the full snapshot `<base>.bak` in the same stack",
+                "type": "object",
+                "required": ["version", "kind", "base"],
+                "properties": {
+                    "version": { "$ref": "#/definitions/version" },
+                    "kind": { "const": "delta" },
+                    "base": {
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                    "added": {
+                        "type": "array",
+                        "items": { "$ref": "#/definitions/branch" },
+                    },
+                    "removed": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                    },
+                    "moved": {
+                        "type": "array",
+                        "items": { "$ref": "#/definitions/branch" },
+                    },
+                    "metadata": { "$ref": "#/definitions/metadata" },
+                    "removed_metadata": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                    },
+                },
+                "additionalProperties": true,
+            },
+            "metadata": {
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "description": "Unique name within the stack from `git branch-stash push --name`",
+                        "type": "string",
+                        "minLength": 1,
+                    },
+                    "pinned": {
+                        "description": "Exempt from capacity eviction, see `git branch-stash pin`",
+                        "type": "boolean",
+                    },
+                    "message": {
+                        "description": "Annotation from `git branch-stash push --message`, a list once pushes of the same refs are merged",
+                        "oneOf": [
+                            { "type": "string" },
+                            { "type": "array", "items": { "type": "string" } },
+                        ],
+                    },
+                    "worktree": {
+                        "description": "Working directory the snapshot was taken from",
+                        "type": "string",
+                    },
+                    "time": {
+                        "description": "When the snapshot was taken, in seconds since the Unix epoch",
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                    "trashed": {
+                        "description": "When the snapshot was moved to the trash, in seconds since the Unix epoch",
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                    "updated": {
+                        "description": "When the same refs were last pushed again, in seconds since the Unix epoch",
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                },
+                "additionalProperties": true,
+            },
+            "oid": {
+                "type": "string",
+                "pattern": "^[0-9a-f]{40}$",
+            },
+            "branch": {
+                "type": "object",
+                "required": ["name", "id"],
+                "properties": {
+                    "name": {
+                        "type": "string",
+                        "minLength": 1,
+                    },
+                    "id": { "$ref": "#/definitions/oid" },
+                    "metadata": {
+                        "type": "object",
+                        "properties": {
+                            "summary": {
+                                "description": "Summary line of the commit the branch pointed to",
+                                "type": "string",
+                            },
+                            "parent": {
+                                "description": "Branch this branch was based on",
+                                "type": "string",
+                            },
+                        },
+                        "additionalProperties": true,
+                    },
+                },
+                "additionalProperties": true,
+            },
+        },
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+pub fn validate(instance: &serde_json::Value) -> Vec<ValidationError> {
+    let schema = snapshot_schema();
+    let mut errors = Vec::new();
+    validate_node(&schema, &schema, instance, "", &mut errors);
+    errors
+}
+
+// Only the keywords used by `snapshot_schema` are supported
+fn validate_node(
+    root: &serde_json::Value,
+    schema: &serde_json::Value,
+    instance: &serde_json::Value,
+    path: &str,
+    errors: &mut Vec<ValidationError>,
+) {
+    let mut error = |message: String| {
+        errors.push(ValidationError {
+            path: path.to_owned(),
+            message,
+        })
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+        match reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+        {
+            Some(schema) => validate_node(root, schema, instance, path, errors),
+            None => error(format!("unresolved schema reference `{}`", reference)),
+        }
+        return;
+    }
+
+    if let Some(alternatives) = schema.get("oneOf").and_then(|o| o.as_array()) {
+        let mut results: Vec<_> = alternatives
+            .iter()
+            .map(|alternative| {
+                let mut alternative_errors = Vec::new();
+                validate_node(root, alternative, instance, path, &mut alternative_errors);
+                alternative_errors
+            })
+            .collect();
+        match results.iter().filter(|e| e.is_empty()).count() {
+            1 => {}
+            0 => {
+                // Report against the alternative whose required properties are present
+                let closest = alternatives
+                    .iter()
+                    .position(|alternative| has_required(root, alternative, instance))
+                    .unwrap_or(0);
+                errors.extend(results.swap_remove(closest));
+            }
+            _ => error("matches more than one alternative".to_owned()),
+        }
+        return;
+    }
+
+    if let Some(expected) = schema.get("const") {
+        if instance != expected {
+            error(format!("expected {}, found {}", expected, instance));
+            return;
+        }
+    }
+
+    if let Some(expected) = schema.get("type").and_then(|t| t.as_str()) {
+        if !is_type(instance, expected) {
+            error(format!(
+                "expected {}, found {}",
+                expected,
+                type_name(instance)
+            ));
+            return;
+        }
+    }
+
+    if let Some(pattern) = schema.get("pattern").and_then(|p| p.as_str()) {
+        if let Some(s) = instance.as_str() {
+            let re = regex::Regex::new(pattern).expect("schema patterns are valid");
+            if !re.is_match(s) {
+                error(format!("{:?} does not match `{}`", s, pattern));
+            }
+        }
+    }
+    if let Some(min) = schema.get("minLength").and_then(|m| m.as_u64()) {
+        if let Some(s) = instance.as_str() {
+            if (s.chars().count() as u64) < min {
+                error(format!("expected at least {} characters", min));
+            }
+        }
+    }
+    if let Some(min) = schema.get("minimum").and_then(|m| m.as_f64()) {
+        if let Some(n) = instance.as_f64() {
+            if n < min {
+                error(format!("{} is less than the minimum of {}", n, min));
+            }
+        }
+    }
+    if let Some(max) = schema.get("maximum").and_then(|m| m.as_f64()) {
+        if let Some(n) = instance.as_f64() {
+            if max < n {
+                error(format!("{} is greater than the maximum of {}", n, max));
+            }
+        }
+    }
+
+    if let Some(object) = instance.as_object() {
+        if let Some(required) = schema.get("required").and_then(|r| r.as_array()) {
+            for key in required.iter().filter_map(|k| k.as_str()) {
+                if !object.contains_key(key) {
+                    error(format!("missing required property `{}`", key));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        for (key, value) in object {
+            let child_path = format!("{}/{}", path, escape_pointer(key));
+            match properties.and_then(|p| p.get(key)) {
+                Some(property) => validate_node(root, property, value, &child_path, errors),
+                None => match schema.get("additionalProperties") {
+                    Some(serde_json::Value::Bool(false)) => errors.push(ValidationError {
+                        path: child_path,
+                        message: "unexpected property".to_owned(),
+                    }),
+                    Some(additional @ serde_json::Value::Object(_)) => {
+                        validate_node(root, additional, value, &child_path, errors)
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    if let Some(array) = instance.as_array() {
+        if let Some(items) = schema.get("items") {
+            for (i, item) in array.iter().enumerate() {
+                let child_path = format!("{}/{}", path, i);
+                validate_node(root, items, item, &child_path, errors);
+            }
+        }
+    }
+}
+
+fn has_required<'s>(
+    root: &'s serde_json::Value,
+    mut schema: &'s serde_json::Value,
+    instance: &serde_json::Value,
+) -> bool {
+    while let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+        match reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+        {
+            Some(target) => schema = target,
+            None => return false,
+        }
+    }
+    let required = schema
+        .get("required")
+        .and_then(|r| r.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|k| k.as_str());
+    match instance.as_object() {
+        Some(object) => required.into_iter().all(|key| object.contains_key(key)),
+        None => false,
+    }
+}
+
+fn is_type(instance: &serde_json::Value, expected: &str) -> bool {
+    match expected {
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "integer" => instance.is_u64() || instance.is_i64(),
+        "number" => instance.is_number(),
+        "boolean" => instance.is_boolean(),
+        "null" => instance.is_null(),
+        _ => false,
+    }
+}
+
+fn type_name(instance: &serde_json::Value) -> &'static str {
+    match instance {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schema_is_current() {
+        let expected = serde_json::to_string_pretty(&snapshot_schema()).unwrap() + "\n";
+        let actual = include_str!("../snapshot.schema.json");
+        assert_eq!(
+            actual, expected,
+            "run `git-branch-stash schema > crates/git-branch-stash/snapshot.schema.json`"
+        );
+    }
+
+    #[test]
+    fn valid_snapshot() {
+        let snapshot = serde_json::json!({
+            "version": 1,
+            "branches": [
+                {
+                    "name": "main",
+                    "id": "0000000000000000000000000000000000000001",
+                    "metadata": { "summary": "Initial commit" },
+                },
+            ],
+            "metadata": { "message": "before rebase" },
+        });
+        assert_eq!(validate(&snapshot), vec![]);
+
+        let merged = serde_json::json!({
+            "version": 1,
+            "branches": [],
+            "metadata": { "message": ["before rebase", "after rebase"] },
+        });
+        assert_eq!(validate(&merged), vec![]);
+    }
+
+    #[test]
+    fn valid_unversioned() {
+        let snapshot = serde_json::json!({
+            "branches": [
+                {
+                    "name": "main",
+                    "id": "0000000000000000000000000000000000000001",
+                },
+            ],
+        });
+        assert_eq!(validate(&snapshot), vec![]);
+
+        let delta = serde_json::json!({ "kind": "delta", "base": 0 });
+        assert!(!validate(&delta).is_empty());
+    }
+
+    #[test]
+    fn valid_delta() {
+        let delta = serde_json::json!({
+            "version": 2,
+            "kind": "delta",
+            "base": 0,
+            "moved": [
+                {
+                    "name": "main",
+                    "id": "0000000000000000000000000000000000000002",
+                },
+            ],
+            "removed": ["feature"],
+        });
+        assert_eq!(validate(&delta), vec![]);
+
+        // A snapshot from another tool that happens to have a `base` field
+        let snapshot = serde_json::json!({
+            "version": 2,
+            "base": 0,
+            "branches": [],
+        });
+        assert_eq!(validate(&snapshot), vec![]);
+    }
+
+    #[test]
+    fn error_locations() {
+        let snapshot = serde_json::json!({
+            "version": 1,
+            "branches": [
+                {
+                    "name": "main",
+                    "id": "0000000000000000000000000000000000000001",
+                },
+                {
+                    "name": "feature",
+                    "id": "not-an-oid",
+                    "metadata": { "parent": 5 },
+                },
+            ],
+        });
+        let errors: Vec<_> = validate(&snapshot).iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "/branches/1/id: \"not-an-oid\" does not match `^[0-9a-f]{40}$`",
+                "/branches/1/metadata/parent: expected string, found number",
+            ]
+        );
+    }
+}
@@ -1,22 +1,52 @@
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Snapshot {
+    pub version: u32,
     pub branches: Vec<Branch>,
     #[serde(default)]
     #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
     pub metadata: std::collections::BTreeMap<String, serde_json::Value>,
+    #[serde(flatten)]
+    pub extra: std::collections::BTreeMap<String, serde_json::Value>,
 }
 
 impl Snapshot {
+    pub const VERSION: u32 = 2;
+
     pub fn load(path: &std::path::Path) -> Result<Self, std::io::Error> {
-        let file = std::fs::File::open(path)?;
-        let reader = std::io::BufReader::new(file);
-        let b = serde_json::from_reader(reader)?;
+        let data = std::fs::read(path)?;
+        let value = serde_json::from_slice(&data)?;
+        if crate::is_delta(&value) {
+            let delta = crate::SnapshotDelta::from_value(value)?;
+            let base_path = delta.base_path(path);
+            let data = std::fs::read(&base_path)?;
+            let base = serde_json::from_slice(&data)?;
+            if crate::is_delta(&base) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} is not a full snapshot", base_path.display()),
+                ));
+            }
+            let base = Self::from_value(base)?;
+            Ok(delta.apply(&base))
+        } else {
+            Self::from_value(value)
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, std::io::Error> {
+        let value = serde_json::from_slice(data)?;
+        Self::from_value(value)
+    }
+
+    pub fn from_value(value: serde_json::Value) -> Result<Self, std::io::Error> {
+        let value = migrate(value)?;
+        let b = serde_json::from_value(value)?;
         Ok(b)
     }
 
     pub fn save(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
         let s = serde_json::to_string_pretty(self)?;
-        std::fs::write(path, &s)?;
+        crate::fs::write_atomic(path, s.as_bytes())?;
         Ok(())
     }
 
@@ -33,15 +63,38 @@ impl Snapshot {
                             String::from_utf8_lossy(commit.summary.as_slice()).into_owned()
                         ),
                     },
+                    extra: Default::default(),
                 }
             })
             .collect();
         branches.sort_unstable();
         let metadata = Default::default();
-        Ok(Self { branches, metadata })
+        Ok(Self {
+            version: Self::VERSION,
+            branches,
+            metadata,
+            extra: Default::default(),
+        })
     }
 
+    // Fails on missing commits, see `recover` to fetch them first
     pub fn apply(&self, repo: &mut dyn crate::git::Repo) -> Result<(), git2::Error> {
+        let missing = self.missing_commits(repo);
+        if !missing.is_empty() {
+            return Err(git2::Error::new(
+                git2::ErrorCode::NotFound,
+                git2::ErrorClass::Odb,
+                format!(
+                    "missing commits for {}",
+                    missing
+                        .iter()
+                        .map(|b| b.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ));
+        }
+
         let head_branch = repo.head_branch();
         let head_branch_name = head_branch.as_ref().map(|b| b.name.as_str());
         for branch in self.branches.iter() {
@@ -63,6 +116,94 @@ impl Snapshot {
         Ok(())
     }
 
+    pub fn missing_commits(&self, repo: &dyn crate::git::Repo) -> Vec<&Branch> {
+        self.branches
+            .iter()
+            .filter(|b| repo.find_commit(b.id).is_none())
+            .collect()
+    }
+
+    // Commits on `branch` since it forked from its recorded `parent`, or `None` when the branch's
+    // commit is gone
+    pub fn branch_commits(
+        &self,
+        repo: &dyn crate::git::Repo,
+        branch: &Branch,
+    ) -> Option<BranchRange> {
+        let tip = repo.find_commit(branch.id)?;
+        let parent_id = match branch.metadata.get("parent") {
+            // Protected branches can be recorded as their own parent
+            Some(serde_json::Value::String(parent)) if parent != &branch.name => self
+                .branches
+                .iter()
+                .find(|b| &b.name == parent)
+                .map(|b| b.id)
+                .or_else(|| repo.find_local_branch(parent).map(|b| b.id)),
+            _ => None,
+        };
+        let base = parent_id
+            .filter(|id| repo.find_commit(*id).is_some())
+            .and_then(|id| repo.merge_base(id, branch.id));
+        let range = match base {
+            Some(base) => BranchRange {
+                commits: repo.commits_since(branch.id, base).collect(),
+                base: Some(base),
+            },
+            None => BranchRange {
+                commits: vec![tip],
+                base: None,
+            },
+        };
+        Some(range)
+    }
+
+    pub fn fetch(&self, repo: &mut dyn crate::git::Repo, source: &str) -> Result<(), git2::Error> {
+        let ids = self.missing_ids(repo);
+        if ids.is_empty() {
+            return Ok(());
+        }
+        log::debug!("Fetching {} missing commits from {}", ids.len(), source);
+        repo.fetch_commits(source, &ids)
+    }
+
+    pub fn recover(&self, repo: &mut dyn crate::git::Repo) -> Result<(), git2::Error> {
+        let ids = self.missing_ids(repo);
+        if ids.is_empty() {
+            return Ok(());
+        }
+        repo.recover_commits(&ids)
+    }
+
+    fn missing_ids(&self, repo: &dyn crate::git::Repo) -> Vec<git2::Oid> {
+        let mut ids: Vec<_> = self.missing_commits(repo).iter().map(|b| b.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.metadata
+            .get("pinned")
+            .and_then(|p| p.as_bool())
+            .unwrap_or(false)
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        if pinned {
+            self.metadata
+                .insert("pinned".to_owned(), serde_json::Value::Bool(true));
+        } else {
+            self.metadata.remove("pinned");
+        }
+    }
+
+    pub fn insert_name(&mut self, name: &str) {
+        self.metadata.insert(
+            "name".to_owned(),
+            serde_json::Value::String(name.to_owned()),
+        );
+    }
+
     pub fn insert_message(&mut self, message: &str) {
         self.metadata.insert(
             "message".to_owned(),
@@ -70,6 +211,96 @@ impl Snapshot {
         );
     }
 
+    /// The latest message, see `messages` for all of them
+    pub fn message(&self) -> Option<&str> {
+        self.messages().pop()
+    }
+
+    // Merged snapshots keep every distinct message, oldest first
+    pub fn messages(&self) -> Vec<&str> {
+        messages(self.metadata.get("message"))
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.metadata.get("name")?.as_str()
+    }
+
+    pub fn insert_time(&mut self, time: std::time::SystemTime) {
+        let seconds = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.metadata
+            .insert("time".to_owned(), serde_json::Value::from(seconds));
+    }
+
+    pub fn time(&self) -> Option<std::time::SystemTime> {
+        let seconds = self.metadata.get("time")?.as_u64()?;
+        Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+    }
+
+    pub fn insert_trashed(&mut self, time: std::time::SystemTime) {
+        let seconds = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.metadata
+            .insert("trashed".to_owned(), serde_json::Value::from(seconds));
+    }
+
+    pub fn trashed(&self) -> Option<std::time::SystemTime> {
+        let seconds = self.metadata.get("trashed")?.as_u64()?;
+        Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+    }
+
+    pub fn same_refs(&self, other: &Self) -> bool {
+        let refs: std::collections::BTreeMap<_, _> =
+            self.branches.iter().map(|b| (&b.name, b.id)).collect();
+        let other_refs: std::collections::BTreeMap<_, _> =
+            other.branches.iter().map(|b| (&b.name, b.id)).collect();
+        refs == other_refs
+    }
+
+    // Fold a newer snapshot of the same refs into this one
+    pub fn merge(&mut self, newer: Snapshot) {
+        for (key, value) in newer.metadata {
+            match (key.as_str(), self.metadata.get(&key)) {
+                ("message", Some(_)) => {
+                    let mut merged: Vec<serde_json::Value> =
+                        self.messages().into_iter().map(Into::into).collect();
+                    for message in messages(Some(&value)) {
+                        if !merged.iter().any(|m| m == message) {
+                            merged.push(message.into());
+                        }
+                    }
+                    let merged = match merged.len() {
+                        1 => merged.pop().unwrap(),
+                        _ => serde_json::Value::Array(merged),
+                    };
+                    self.metadata.insert(key, merged);
+                }
+                ("time", Some(_)) => {
+                    self.metadata.insert("updated".to_owned(), value);
+                }
+                _ => {
+                    self.metadata.insert(key, value);
+                }
+            }
+        }
+        for newer in newer.branches {
+            if let Some(branch) = self.branches.iter_mut().find(|b| b.name == newer.name) {
+                branch.metadata.extend(newer.metadata);
+            }
+        }
+    }
+
+    pub fn insert_worktree(&mut self, worktree: &std::path::Path) {
+        self.metadata.insert(
+            "worktree".to_owned(),
+            serde_json::Value::String(worktree.display().to_string()),
+        );
+    }
+
     pub fn insert_parent(
         &mut self,
         repo: &dyn crate::git::Repo,
@@ -89,6 +320,14 @@ impl Snapshot {
     }
 }
 
+// A branch's own commits, newest first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BranchRange {
+    pub commits: Vec<std::rc::Rc<crate::git::Commit>>,
+    // Where the branch forked from its parent, `None` when unknown and `commits` is only the tip
+    pub base: Option<git2::Oid>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Branch {
     pub name: String,
@@ -98,9 +337,65 @@ pub struct Branch {
     #[serde(default)]
     #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
     pub metadata: std::collections::BTreeMap<String, serde_json::Value>,
+    #[serde(flatten)]
+    pub extra: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+pub fn format_version(value: &serde_json::Value) -> Result<u32, std::io::Error> {
+    match value.get("version") {
+        // Snapshots from before versioning was introduced
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| std::convert::TryFrom::try_from(v).ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid snapshot version {}", version),
+                )
+            }),
+    }
+}
+
+pub(crate) fn check_version(value: &serde_json::Value) -> Result<u32, std::io::Error> {
+    let version = format_version(value)?;
+    if Snapshot::VERSION < version {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "snapshot version {} is newer than the supported version {}",
+                version,
+                Snapshot::VERSION
+            ),
+        ));
+    }
+    Ok(version)
 }
 
-fn serialize_oid<S>(id: &git2::Oid, serializer: S) -> Result<S::Ok, S::Error>
+fn migrate(mut value: serde_json::Value) -> Result<serde_json::Value, std::io::Error> {
+    let version = check_version(&value)?;
+
+    // - Version 1 added the `version` field
+    // - Version 2 added delta-encoded snapshots (`SnapshotDelta`)
+    // Neither changed the layout of full snapshots
+    if version < Snapshot::VERSION {
+        log::trace!(
+            "Migrating snapshot from version {} to {}",
+            version,
+            Snapshot::VERSION
+        );
+        if let Some(object) = value.as_object_mut() {
+            object.insert(
+                "version".to_owned(),
+                serde_json::Value::from(Snapshot::VERSION),
+            );
+        }
+    }
+
+    Ok(value)
+}
+
+pub(crate) fn serialize_oid<S>(id: &git2::Oid, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
@@ -119,7 +414,7 @@ where
 
 impl PartialOrd for Branch {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some((&self.name, self.id).cmp(&(&other.name, other.id)))
+        Some(self.cmp(other))
     }
 }
 
@@ -128,3 +423,144 @@ impl Ord for Branch {
         (&self.name, self.id).cmp(&(&other.name, other.id))
     }
 }
+
+fn messages(value: Option<&serde_json::Value>) -> Vec<&str> {
+    match value {
+        Some(serde_json::Value::String(message)) => vec![message.as_str()],
+        Some(serde_json::Value::Array(messages)) => {
+            messages.iter().filter_map(|m| m.as_str()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{git_commit, git_merge, oid, snapshot, with_parent};
+
+    fn fixture() -> (tempfile::TempDir, git2::Repository, git2::Oid) {
+        let root = tempfile::tempdir().unwrap();
+
+        let upstream = git2::Repository::init(root.path().join("upstream")).unwrap();
+        let base_id = git_commit(&upstream, None, ("base", "base"), "base");
+        let feature_id = git_commit(&upstream, Some(base_id), ("feature", "feature"), "feature");
+        let feature = upstream.find_commit(feature_id).unwrap();
+        upstream.branch("main", &feature, false).unwrap();
+
+        let local = git2::Repository::init(root.path().join("local")).unwrap();
+        let local_id = git_commit(&local, None, ("local", "local"), "local");
+        local
+            .branch("main", &local.find_commit(local_id).unwrap(), false)
+            .unwrap();
+        local.set_head("refs/heads/main").unwrap();
+        let url = format!("file://{}", upstream.path().display());
+        local.remote("origin", &url).unwrap();
+
+        (root, local, feature_id)
+    }
+
+    #[test]
+    fn recover_from_remote() {
+        let (_root, local, feature_id) = fixture();
+        let mut repo = crate::git::GitRepo::new(local);
+        let snapshot = snapshot(&[("feature", feature_id)]);
+
+        assert_eq!(snapshot.missing_commits(&repo).len(), 1);
+        assert!(snapshot.apply(&mut repo).is_err());
+        snapshot.recover(&mut repo).unwrap();
+        assert!(snapshot.missing_commits(&repo).is_empty());
+        snapshot.apply(&mut repo).unwrap();
+        assert_eq!(
+            repo.find_local_branch("feature").map(|b| b.id),
+            Some(feature_id)
+        );
+    }
+
+    #[test]
+    fn apply_offline_fails_on_missing() {
+        let (_root, local, feature_id) = fixture();
+        let mut repo = crate::git::GitRepo::new(local);
+        repo.set_offline(true);
+        let snapshot = snapshot(&[("feature", feature_id)]);
+
+        snapshot.recover(&mut repo).unwrap();
+        assert!(snapshot.apply(&mut repo).is_err());
+        assert_eq!(repo.find_local_branch("feature"), None);
+    }
+
+    #[test]
+    fn branch_commits() {
+        let root = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(root.path()).unwrap();
+        let base_id = git_commit(&raw, None, ("base", "base"), "base");
+        let first_id = git_commit(&raw, Some(base_id), ("first", "first"), "first");
+        let main_id = git_commit(&raw, Some(base_id), ("main", "main"), "main");
+        let merge_id = git_merge(&raw, &[first_id, main_id], "merge main");
+        let second_id = git_commit(&raw, Some(merge_id), ("second", "second"), "second");
+        let repo = crate::git::GitRepo::new(raw);
+
+        let snapshot = with_parent(
+            with_parent(
+                snapshot(&[("main", main_id), ("feature", second_id), ("gone", oid(1))]),
+                "feature",
+                "main",
+            ),
+            "gone",
+            "main",
+        );
+
+        let range = |b: &Branch| {
+            snapshot.branch_commits(&repo, b).map(|range| {
+                let ids: Vec<_> = range.commits.iter().map(|c| c.id).collect();
+                (ids, range.base)
+            })
+        };
+        assert_eq!(range(&snapshot.branches[0]), Some((vec![main_id], None)));
+        assert_eq!(
+            range(&snapshot.branches[1]),
+            Some((vec![second_id, merge_id, first_id], Some(main_id)))
+        );
+        assert_eq!(range(&snapshot.branches[2]), None);
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let data = br#"{
+  "branches": [
+    {
+      "name": "main",
+      "id": "0000000000000000000000000000000000000001"
+    }
+  ]
+}"#;
+        let snapshot = Snapshot::from_slice(data).unwrap();
+        assert_eq!(snapshot.version, Snapshot::VERSION);
+        assert_eq!(snapshot.branches.len(), 1);
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let data = br#"{"version": 999, "branches": []}"#;
+        assert!(Snapshot::from_slice(data).is_err());
+    }
+
+    #[test]
+    fn preserve_unknown_fields() {
+        let data = br#"{
+  "version": 2,
+  "branches": [
+    {
+      "name": "main",
+      "id": "0000000000000000000000000000000000000001",
+      "upstream": "origin/main"
+    }
+  ],
+  "host": "build-server"
+}"#;
+        let snapshot = Snapshot::from_slice(data).unwrap();
+        let value = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(value["host"], "build-server");
+        assert_eq!(value["branches"][0]["upstream"], "origin/main");
+    }
+}
@@ -1,54 +1,90 @@
 pub use super::Snapshot;
+use super::SnapshotDelta;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Stack {
     pub name: String,
     root: std::path::PathBuf,
     capacity: Option<usize>,
+    pins_count: bool,
+    retention: crate::Retention,
+    trash_root: Option<std::path::PathBuf>,
+    trash_expiry: Option<std::time::Duration>,
 }
 
 impl Stack {
     pub const DEFAULT_STACK: &'static str = "recent";
-    const EXT: &'static str = "bak";
+    pub(crate) const EXT: &'static str = "bak";
+    // Cache of which snapshots are pinned, see `Stack::pinned`
+    pub(crate) const PINS: &'static str = ".pins";
+    const KEYFRAME_INTERVAL: usize = 10;
 
-    pub fn new(name: &str, repo: &crate::git::GitRepo) -> Self {
-        let root = stack_root(repo.raw().path(), name);
-        let name = name.to_owned();
+    pub fn new(name: &str, repo: &crate::git::GitRepo) -> eyre::Result<Self> {
+        crate::Store::in_repo(repo).stack(name)
+    }
+
+    pub fn with_root(name: &str, root: impl Into<std::path::PathBuf>) -> Self {
         Self {
-            name,
-            root,
+            name: name.to_owned(),
+            root: root.into(),
             capacity: None,
+            pins_count: false,
+            retention: Default::default(),
+            trash_root: None,
+            trash_expiry: None,
         }
     }
 
+    pub fn with_trash(mut self, trash_root: impl Into<std::path::PathBuf>) -> Self {
+        self.trash_root = Some(trash_root.into());
+        self
+    }
+
     pub fn all(repo: &crate::git::GitRepo) -> impl Iterator<Item = Self> {
-        let root = stacks_root(repo.raw().path());
-        let mut stacks: Vec<_> = std::fs::read_dir(root)
-            .into_iter()
-            .flatten()
-            .filter_map(|e| {
-                let e = e.ok()?;
-                let e = e.file_type().ok()?.is_dir().then(|| e)?;
-                let p = e.path();
-                let stack_name = p.file_name()?.to_str()?.to_owned();
-                let stack_root = stack_root(repo.raw().path(), &stack_name);
-                Some(Self {
-                    name: stack_name,
-                    root: stack_root,
-                    capacity: None,
-                })
-            })
-            .collect();
-        if !stacks.iter().any(|v| v.name == Self::DEFAULT_STACK) {
-            stacks.insert(0, Self::new(Self::DEFAULT_STACK, repo));
+        crate::Store::in_repo(repo).stacks()
+    }
+
+    // Stack names follow git's ref name rules, with `/` separating a hierarchy
+    pub fn check_name(name: &str) -> eyre::Result<()> {
+        if name.is_empty() {
+            eyre::bail!("stack name cannot be empty");
+        }
+        if name.contains(char::is_control)
+            || !git2::Reference::is_valid_name(&format!("refs/branch-stash/{}", name))
+        {
+            eyre::bail!("`{}` is not a valid stack name", name);
         }
-        stacks.into_iter()
+        Ok(())
+    }
+
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
     }
 
     pub fn capacity(&mut self, capacity: Option<usize>) {
         self.capacity = capacity;
     }
 
+    pub fn pins_count(&mut self, pins_count: bool) {
+        self.pins_count = pins_count;
+    }
+
+    pub fn retention(&mut self, retention: crate::Retention) {
+        self.retention = retention;
+    }
+
+    pub fn trash_expiry(&mut self, expiry: Option<std::time::Duration>) {
+        self.trash_expiry = expiry;
+    }
+
+    pub fn configure(&mut self, config: &crate::config::RepoConfig) {
+        let config = config.for_stack(&self.name);
+        self.capacity(config.capacity());
+        self.pins_count(config.pins_count());
+        self.retention(config.retention());
+        self.trash_expiry(Some(config.trash_expiry()));
+    }
+
     pub fn iter(&self) -> impl DoubleEndedIterator<Item = std::path::PathBuf> {
         let mut elements: Vec<(usize, std::path::PathBuf)> = std::fs::read_dir(&self.root)
             .into_iter()
@@ -66,61 +102,538 @@ impl Stack {
         elements.into_iter().map(|(_, p)| p)
     }
 
+    // Held while modifying the stack so concurrent pushes don't claim the same index
+    pub(crate) fn lock(&self) -> Result<crate::fs::Lock, std::io::Error> {
+        let name = self.root.file_name().unwrap_or_default().to_string_lossy();
+        crate::fs::Lock::acquire(self.root.with_file_name(format!("{}.lock", name)))
+    }
+
     pub fn push(&mut self, snapshot: Snapshot) -> Result<std::path::PathBuf, std::io::Error> {
+        let _lock = self.lock()?;
+        // Pushing the latest snapshot again under its own name is a no-op, not a duplicate
+        let last = self.iter().last().and_then(|p| Snapshot::load(&p).ok());
+        if !last.map_or(false, |last| merges_into(&last, &snapshot)) {
+            self.check_unique(&snapshot)?;
+        }
+        self.push_locked(snapshot)
+    }
+
+    // Push `snapshot`, removing any snapshot with the same name once the new one is written
+    pub fn replace(&mut self, snapshot: Snapshot) -> Result<std::path::PathBuf, std::io::Error> {
+        let _lock = self.lock()?;
+        let existing = snapshot.name().and_then(|name| self.find_named(name));
+        let path = self.push_locked(snapshot)?;
+        if let Some(existing) = existing {
+            // Capacity may have already evicted it
+            if existing != path && existing.exists() {
+                log::debug!("Replacing {}", existing.display());
+                self.remove(&[existing])?;
+            }
+        }
+        Ok(path)
+    }
+
+    // Add `snapshot` as-is, without merging into the latest snapshot or evicting old ones
+    pub fn append(&mut self, snapshot: Snapshot) -> Result<std::path::PathBuf, std::io::Error> {
+        let _lock = self.lock()?;
+        self.check_unique(&snapshot)?;
+        self.append_locked(&snapshot)
+    }
+
+    pub(crate) fn check_unique(&self, snapshot: &Snapshot) -> Result<(), std::io::Error> {
+        if let Some(name) = snapshot.name() {
+            if self.find_named(name).is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("snapshot `{}` already exists in `{}`", name, self.name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The `*_locked` functions expect the caller to hold `Stack::lock`
+    fn push_locked(&mut self, snapshot: Snapshot) -> Result<std::path::PathBuf, std::io::Error> {
         let elems: Vec<_> = self.iter().collect();
         let last_path = elems.iter().last();
-        let next_index = match last_path {
-            Some(last_path) => {
-                let current_index = last_path
-                    .file_stem()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap();
-                current_index + 1
-            }
-            None => 0,
-        };
-        let last = last_path.and_then(|p| Snapshot::load(p).ok());
-        if last.as_ref() == Some(&snapshot) {
-            let last_path = last_path.unwrap().to_owned();
-            log::trace!("Reusing snapshot {}", last_path.display());
-            return Ok(last_path);
+        let last = last_path.and_then(|p| Some((p, Snapshot::load(p).ok()?)));
+        if let Some((last_path, mut last)) = last {
+            if merges_into(&last, &snapshot) {
+                let original = last.clone();
+                last.merge(snapshot);
+                if last == original {
+                    log::trace!("Reusing snapshot {}", last_path.display());
+                } else {
+                    self.rewrite(last_path, &last)?;
+                    log::trace!("Updated snapshot {}", last_path.display());
+                }
+                return Ok(last_path.to_owned());
+            }
         }
 
-        std::fs::create_dir_all(&self.root)?;
-        let new_path = self.root.join(format!("{}.{}", next_index, Self::EXT));
-        snapshot.save(&new_path)?;
-        log::trace!("Backed up as {}", new_path.display());
+        let new_path = self.append_locked(&snapshot)?;
 
         if let Some(capacity) = self.capacity {
-            let len = elems.len();
+            // Capacity is what's kept, including the new snapshot
+            let pinned = self.pinned(&elems);
+            let unpinned: Vec<_> = elems
+                .iter()
+                .zip(pinned)
+                .filter(|(_, pinned)| !pinned)
+                .map(|(p, _)| p.clone())
+                .collect();
+            let len = if self.pins_count {
+                elems.len()
+            } else {
+                unpinned.len()
+            } + 1;
             if capacity < len {
-                let remove = len - capacity;
-                log::warn!("Too many snapshots, clearing {} oldest", remove);
-                for snapshot_path in &elems[0..remove] {
-                    if let Err(err) = std::fs::remove_file(&snapshot_path) {
-                        log::trace!("Failed to remove {}: {}", snapshot_path.display(), err);
-                    } else {
-                        log::trace!("Removed {}", snapshot_path.display());
-                    }
+                let remove = (len - capacity).min(unpinned.len());
+                if remove < len - capacity {
+                    log::warn!(
+                        "Pinned snapshots exceed the capacity of {}, unpin some with `git branch-stash unpin`",
+                        capacity
+                    );
                 }
+                if remove != 0 {
+                    log::warn!("Too many snapshots, clearing {} oldest", remove);
+                    self.remove(&unpinned[0..remove])?;
+                }
+            }
+        }
+
+        if self.retention.is_enabled() {
+            let pruned = self.prune_locked(std::time::SystemTime::now())?;
+            if !pruned.is_empty() {
+                log::debug!("Pruned {} snapshots past retention", pruned.len());
             }
         }
 
+        let purged = self.purge_trash_locked(std::time::SystemTime::now())?;
+        if purged != 0 {
+            log::debug!("Purged {} expired snapshots from the trash", purged);
+        }
+
+        Ok(new_path)
+    }
+
+    pub(crate) fn append_locked(
+        &mut self,
+        snapshot: &Snapshot,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let elems: Vec<_> = self.iter().collect();
+        let next_index = elems
+            .last()
+            .and_then(|p| index(p))
+            .map_or(0, |current_index| current_index + 1);
+
+        std::fs::create_dir_all(&self.root)?;
+        let new_path = self.root.join(format!("{}.{}", next_index, Self::EXT));
+        match self.delta(&elems, snapshot) {
+            Some(delta) => {
+                delta.save(&new_path)?;
+                log::trace!(
+                    "Backed up as {} (delta against {})",
+                    new_path.display(),
+                    delta.base
+                );
+            }
+            None => {
+                snapshot.save(&new_path)?;
+                log::trace!("Backed up as {}", new_path.display());
+            }
+        }
         Ok(new_path)
     }
 
-    pub fn clear(&mut self) {
-        let _ = std::fs::remove_dir_all(&self.root);
+    fn delta(&self, elems: &[std::path::PathBuf], snapshot: &Snapshot) -> Option<SnapshotDelta> {
+        let (since_keyframe, keyframe_path) = elems
+            .iter()
+            .rev()
+            .enumerate()
+            .find(|(_, p)| matches!(delta_base(p), Ok(None)))?;
+        if Self::KEYFRAME_INTERVAL <= since_keyframe + 1 {
+            return None;
+        }
+
+        if !SnapshotDelta::can_encode(snapshot) {
+            return None;
+        }
+        let keyframe = Snapshot::load(keyframe_path).ok()?;
+        let delta = SnapshotDelta::new(index(keyframe_path)?, &keyframe, snapshot);
+        // Only worth it when most branches are unchanged
+        (delta.len() * 2 < snapshot.branches.len()).then(|| delta)
+    }
+
+    fn rewrite(&self, path: &std::path::Path, snapshot: &Snapshot) -> Result<(), std::io::Error> {
+        match delta_base(path)? {
+            Some(base) => {
+                let base_path = path.with_file_name(format!("{}.{}", base, Self::EXT));
+                let keyframe = load(&base_path)?;
+                SnapshotDelta::new(base, &keyframe, snapshot).save(path)
+            }
+            None => {
+                // Dependents only record what differs from this keyframe
+                let keyframe_index = index(path).unwrap_or_default();
+                let mut dependents = Vec::new();
+                for dependent in self.iter() {
+                    if !matches!(delta_base(&dependent), Ok(Some(base)) if base == keyframe_index) {
+                        continue;
+                    }
+                    match load(&dependent) {
+                        Ok(dependent_snapshot) => dependents.push((dependent, dependent_snapshot)),
+                        Err(err) => log::warn!("Skipping unreadable snapshot {}", err),
+                    }
+                }
+                snapshot.save(path)?;
+                for (dependent, dependent_snapshot) in dependents {
+                    SnapshotDelta::new(keyframe_index, snapshot, &dependent_snapshot)
+                        .save(&dependent)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn expired(
+        &self,
+        now: std::time::SystemTime,
+    ) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+        let mut snapshots = Vec::new();
+        for path in self.iter() {
+            // Left for `fsck` rather than expired
+            let snapshot = match load(&path) {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    log::warn!("Skipping unreadable snapshot {}", err);
+                    continue;
+                }
+            };
+            let time = created(&path, &snapshot)?;
+            snapshots.push(((path, snapshot.is_pinned()), time));
+        }
+        let expired = self
+            .retention
+            .expired(&snapshots, now)
+            .into_iter()
+            .filter(|(_, pinned)| !pinned)
+            .map(|(path, _)| path.clone())
+            .collect();
+        Ok(expired)
+    }
+
+    pub fn prune(
+        &mut self,
+        now: std::time::SystemTime,
+    ) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+        let _lock = self.lock()?;
+        self.prune_locked(now)
+    }
+
+    fn prune_locked(
+        &mut self,
+        now: std::time::SystemTime,
+    ) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+        let expired = self.expired(now)?;
+        self.remove(&expired)?;
+        Ok(expired)
+    }
+
+    pub fn find_named(&self, name: &str) -> Option<std::path::PathBuf> {
+        self.iter()
+            .rev()
+            .find(|p| Snapshot::load(p).map_or(false, |s| s.name() == Some(name)))
+    }
+
+    pub fn remove_snapshot(&mut self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let _lock = self.lock()?;
+        self.remove(&[path.to_owned()])
+    }
+
+    // Remove without keeping a copy in the trash, like when the snapshot was moved elsewhere
+    pub fn forget_snapshot(&mut self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let _lock = self.lock()?;
+        let path = path.to_owned();
+        self.rebase(std::slice::from_ref(&path))?;
+        std::fs::remove_file(&path)?;
+        log::trace!("Removed {}", path.display());
+        Ok(())
+    }
+
+    fn remove(&mut self, paths: &[std::path::PathBuf]) -> Result<(), std::io::Error> {
+        // Capture snapshots before their keyframes go away
+        let trashed: Vec<_> = match self.trash() {
+            Some(_) => paths.iter().map(|p| (p, Snapshot::load(p))).collect(),
+            None => Vec::new(),
+        };
+        self.rebase(paths)?;
+        for (path, snapshot) in trashed {
+            self.move_to_trash(path, snapshot)?;
+        }
+        for snapshot_path in paths {
+            std::fs::remove_file(snapshot_path)?;
+            log::trace!("Removed {}", snapshot_path.display());
+        }
+        Ok(())
+    }
+
+    fn move_to_trash(
+        &self,
+        path: &std::path::Path,
+        snapshot: Result<Snapshot, std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        let trash = match self.trash() {
+            Some(trash) => trash,
+            None => return Ok(()),
+        };
+        let next_index = trash
+            .iter()
+            .last()
+            .and_then(|p| index(&p))
+            .map_or(0, |i| i + 1);
+        std::fs::create_dir_all(&trash.root)?;
+        let trash_path = trash.root.join(format!("{}.{}", next_index, Self::EXT));
+        match snapshot {
+            Ok(mut snapshot) => {
+                snapshot.insert_trashed(std::time::SystemTime::now());
+                snapshot.save(&trash_path)?;
+            }
+            Err(err) => {
+                // Keep what we can't parse as-is rather than losing it
+                log::debug!("Trashing unreadable {}: {}", path.display(), err);
+                std::fs::copy(path, &trash_path)?;
+            }
+        }
+        log::trace!("Trashed {} as {}", path.display(), trash_path.display());
+        Ok(())
+    }
+
+    pub fn trash(&self) -> Option<Stack> {
+        let trash_root = self.trash_root.as_ref()?;
+        Some(Stack::with_root(&self.name, trash_root))
+    }
+
+    // Add a trashed snapshot back as-is, like `append`
+    pub fn restore(
+        &mut self,
+        trash_path: &std::path::Path,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let _lock = self.lock()?;
+        let mut snapshot = Snapshot::load(trash_path)?;
+        snapshot.metadata.remove("trashed");
+        self.check_unique(&snapshot)?;
+        let path = self.append_locked(&snapshot)?;
+        std::fs::remove_file(trash_path)?;
+        log::trace!("Restored {} as {}", trash_path.display(), path.display());
+        Ok(path)
+    }
+
+    pub fn purge_trash(&mut self, now: std::time::SystemTime) -> Result<usize, std::io::Error> {
+        let _lock = self.lock()?;
+        self.purge_trash_locked(now)
+    }
+
+    fn purge_trash_locked(&mut self, now: std::time::SystemTime) -> Result<usize, std::io::Error> {
+        let (trash, expiry) = match (self.trash(), self.trash_expiry) {
+            (Some(trash), Some(expiry)) => (trash, expiry),
+            _ => return Ok(0),
+        };
+        let mut purged = 0;
+        for path in trash.iter() {
+            let trashed = match Snapshot::load(&path).ok().and_then(|s| s.trashed()) {
+                Some(trashed) => trashed,
+                None => std::fs::metadata(&path)?.modified()?,
+            };
+            if trashed
+                .checked_add(expiry)
+                .map_or(false, |expires| expires < now)
+            {
+                std::fs::remove_file(&path)?;
+                log::trace!("Purged {}", path.display());
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    // Rewrite deltas whose keyframe is about to be removed
+    fn rebase(&self, removing: &[std::path::PathBuf]) -> Result<(), std::io::Error> {
+        let removing: std::collections::HashSet<_> =
+            removing.iter().filter_map(|p| index(p)).collect();
+        let mut keyframes: std::collections::HashMap<usize, (usize, Snapshot)> = Default::default();
+        for path in self.iter() {
+            let current = match index(&path) {
+                Some(current) if !removing.contains(&current) => current,
+                _ => continue,
+            };
+            let base = match delta_base(&path) {
+                Ok(Some(base)) if removing.contains(&base) => base,
+                Ok(_) => continue,
+                Err(err) => {
+                    log::warn!("Skipping unreadable snapshot {}", err);
+                    continue;
+                }
+            };
+
+            let snapshot = match load(&path) {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    log::warn!("Skipping unreadable snapshot {}", err);
+                    continue;
+                }
+            };
+            match keyframes.get(&base) {
+                Some((keyframe_index, keyframe)) => {
+                    SnapshotDelta::new(*keyframe_index, keyframe, &snapshot).save(&path)?;
+                    log::trace!("Rebased {} onto {}", path.display(), keyframe_index);
+                }
+                None => {
+                    snapshot.save(&path)?;
+                    log::trace!("Expanded {} to a full snapshot", path.display());
+                    keyframes.insert(base, (current, snapshot));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn migrate(&mut self) -> Result<usize, std::io::Error> {
+        let _lock = self.lock()?;
+        let mut migrated = 0;
+        for path in self.iter() {
+            let data = std::fs::read(&path).map_err(|e| file_error(&path, e))?;
+            let value: serde_json::Value =
+                serde_json::from_slice(&data).map_err(|e| file_error(&path, e.into()))?;
+            if crate::format_version(&value).map_err(|e| file_error(&path, e))? == Snapshot::VERSION
+            {
+                continue;
+            }
+            let snapshot = Snapshot::from_value(value).map_err(|e| file_error(&path, e))?;
+            snapshot.save(&path)?;
+            log::trace!("Migrated {}", path.display());
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+
+    // Close gaps in the numbering, rewriting moved snapshots in full
+    pub fn renumber(&mut self) -> Result<usize, std::io::Error> {
+        let _lock = self.lock()?;
+        let paths: Vec<_> = self.iter().collect();
+        // Load everything up front, deltas need their keyframes where they were
+        let snapshots = paths
+            .iter()
+            .map(|p| load(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Every snapshot moves down, so a file is only overwritten after it was copied lower.
+        // Removing the leftovers last means a crash leaves duplicates rather than losing any.
+        let mut renamed = Vec::new();
+        for (i, (path, snapshot)) in paths.iter().zip(snapshots).enumerate() {
+            if index(path) == Some(i) {
+                continue;
+            }
+            let new_path = self.root.join(format!("{}.{}", i, Self::EXT));
+            snapshot.save(&new_path)?;
+            renamed.push((path, new_path));
+        }
+        let new_paths: std::collections::HashSet<_> = renamed
+            .iter()
+            .map(|(_, new_path)| new_path.clone())
+            .collect();
+        for (path, new_path) in renamed.iter() {
+            if !new_paths.contains(*path) {
+                std::fs::remove_file(path)?;
+            }
+            log::trace!("Renumbered {} as {}", path.display(), new_path.display());
+        }
+        Ok(renamed.len())
+    }
+
+    pub fn clear(&mut self) -> Result<(), std::io::Error> {
+        let _lock = self.lock()?;
+        let paths: Vec<_> = self.iter().collect();
+        let unpinned: Vec<_> = paths
+            .iter()
+            .zip(self.pinned(&paths))
+            .filter(|(_, pinned)| !pinned)
+            .map(|(p, _)| p.clone())
+            .collect();
+        self.remove(&unpinned)?;
+        if self.iter().next().is_none() {
+            self.clear_all_locked()?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_all(&mut self) -> Result<(), std::io::Error> {
+        let _lock = self.lock()?;
+        self.clear_all_locked()
+    }
+
+    fn clear_all_locked(&mut self) -> Result<(), std::io::Error> {
+        let paths: Vec<_> = self.iter().collect();
+        self.remove(&paths)?;
+        let _ = std::fs::remove_file(self.root.join(Self::PINS));
+        // Nested stacks, like `team/alice` under `team`, keep the directory alive
+        let _ = std::fs::remove_dir(&self.root);
+        Ok(())
+    }
+
+    pub fn set_pinned(
+        &mut self,
+        path: &std::path::Path,
+        pinned: bool,
+    ) -> Result<(), std::io::Error> {
+        let _lock = self.lock()?;
+        let mut snapshot = load(path)?;
+        snapshot.set_pinned(pinned);
+        self.rewrite(path, &snapshot)
     }
 
-    pub fn pop(&mut self) -> Option<std::path::PathBuf> {
-        let mut elems: Vec<_> = self.iter().collect();
-        let last = elems.pop()?;
-        std::fs::remove_file(&last).ok()?;
-        Some(last)
+    // Whether each of `paths` is pinned, loading only the snapshots changed since last asked
+    fn pinned(&self, paths: &[std::path::PathBuf]) -> Vec<bool> {
+        let cache_path = self.root.join(Self::PINS);
+        let cache: std::collections::BTreeMap<String, PinState> = std::fs::read(&cache_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        let mut updated = std::collections::BTreeMap::new();
+        let mut pinned = Vec::with_capacity(paths.len());
+        for path in paths {
+            let key = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let state = match (PinState::stat(path), cache.get(&key)) {
+                (Some(stat), Some(cached)) if stat.same_file(cached) => cached.clone(),
+                (stat, _) => {
+                    let is_pinned = Snapshot::load(path).map_or(false, |s| s.is_pinned());
+                    let mut state = stat.unwrap_or_default();
+                    state.pinned = is_pinned;
+                    state
+                }
+            };
+            pinned.push(state.pinned);
+            updated.insert(key, state);
+        }
+        if updated != cache {
+            let data = serde_json::to_vec(&updated).expect("pin states serialize");
+            if let Err(err) = crate::fs::write_atomic(&cache_path, &data) {
+                log::debug!("Failed to cache pins in {}: {}", cache_path.display(), err);
+            }
+        }
+        pinned
+    }
+
+    pub fn pop(&mut self) -> Result<Option<std::path::PathBuf>, std::io::Error> {
+        let _lock = self.lock()?;
+        let last = match self.iter().last() {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        self.remove(std::slice::from_ref(&last))?;
+        Ok(Some(last))
     }
 
     pub fn peek(&mut self) -> Option<std::path::PathBuf> {
@@ -128,10 +641,405 @@ impl Stack {
     }
 }
 
-fn stacks_root(repo: &std::path::Path) -> std::path::PathBuf {
-    repo.join("branch-stash")
+// Unchanged refs are folded into the latest snapshot, unless that would name or rename it
+fn merges_into(last: &Snapshot, snapshot: &Snapshot) -> bool {
+    last.same_refs(snapshot) && (snapshot.name().is_none() || snapshot.name() == last.name())
 }
 
-fn stack_root(repo: &std::path::Path, stack: &str) -> std::path::PathBuf {
-    repo.join("branch-stash").join(stack)
+// Rewriting a snapshot replaces the file, so its size and mtime tell when `pinned` is stale
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct PinState {
+    len: u64,
+    modified: (u64, u32),
+    pinned: bool,
+}
+
+impl PinState {
+    fn stat(path: &std::path::Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+            pinned: false,
+        })
+    }
+
+    fn same_file(&self, other: &Self) -> bool {
+        self.len == other.len && self.modified == other.modified
+    }
+}
+
+pub(crate) fn index(path: &std::path::Path) -> Option<usize> {
+    path.file_stem()?.to_str()?.parse::<usize>().ok()
+}
+
+pub(crate) fn created(
+    path: &std::path::Path,
+    snapshot: &Snapshot,
+) -> Result<std::time::SystemTime, std::io::Error> {
+    match snapshot.time() {
+        Some(time) => Ok(time),
+        // Snapshots from before `time` was recorded
+        None => std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map_err(|e| file_error(path, e)),
+    }
+}
+
+fn load(path: &std::path::Path) -> Result<Snapshot, std::io::Error> {
+    Snapshot::load(path).map_err(|e| file_error(path, e))
+}
+
+// Say which file in the stack is the problem
+fn file_error(path: &std::path::Path, err: std::io::Error) -> std::io::Error {
+    std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+fn delta_base(path: &std::path::Path) -> Result<Option<usize>, std::io::Error> {
+    let data = std::fs::read(path).map_err(|e| file_error(path, e))?;
+    let value: serde_json::Value =
+        serde_json::from_slice(&data).map_err(|e| file_error(path, e.into()))?;
+    if !crate::is_delta(&value) {
+        return Ok(None);
+    }
+    Ok(value
+        .get("base")
+        .and_then(|b| b.as_u64())
+        .map(|b| b as usize))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(ids: &[u32]) -> Snapshot {
+        let ids: Vec<_> = ids.iter().map(|id| crate::testing::oid(*id)).collect();
+        crate::testing::numbered(&ids)
+    }
+
+    #[test]
+    fn push_merges_same_refs() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+
+        let mut first = snapshot(&[1, 2]);
+        first.insert_message("first");
+        stack.push(first).unwrap();
+        let mut second = snapshot(&[1, 2]);
+        second.insert_message("second");
+        let path = stack.push(second).unwrap();
+
+        assert_eq!(stack.iter().count(), 1);
+        let actual = Snapshot::load(&path).unwrap();
+        assert_eq!(
+            actual.metadata.get("message"),
+            Some(&serde_json::json!(["first", "second"]))
+        );
+        assert_eq!(actual.message(), Some("second"));
+
+        let mut third = snapshot(&[1, 2]);
+        third.insert_message("first; second");
+        let path = stack.push(third).unwrap();
+        let actual = Snapshot::load(&path).unwrap();
+        assert_eq!(actual.messages(), ["first", "second", "first; second"]);
+
+        stack.push(snapshot(&[1, 3])).unwrap();
+        assert_eq!(stack.iter().count(), 2);
+    }
+
+    #[test]
+    fn append_keeps_same_refs() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+
+        stack.push(snapshot(&[1, 2])).unwrap();
+        let mut copy = snapshot(&[1, 2]);
+        copy.insert_message("copy");
+        let path = stack.append(copy.clone()).unwrap();
+
+        assert_eq!(stack.iter().count(), 2);
+        assert_eq!(Snapshot::load(&path).unwrap(), copy);
+    }
+
+    #[test]
+    fn names_survive_eviction() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+        stack.capacity(Some(3));
+
+        let first = stack.push(snapshot(&[1, 2, 3])).unwrap();
+        let mut named = snapshot(&[1, 2, 3, 4]);
+        named.insert_name("before-rebase");
+        stack.push(named.clone()).unwrap();
+        for i in 0..2 {
+            stack.push(snapshot(&[1, 2, 3, 10 + i])).unwrap();
+        }
+        assert!(!first.exists());
+
+        let path = stack.find_named("before-rebase").unwrap();
+        assert_eq!(Snapshot::load(&path).unwrap(), named);
+        assert_eq!(stack.find_named("after-rebase"), None);
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+
+        let mut first = snapshot(&[1, 2]);
+        first.insert_name("wip");
+        let first_path = stack.push(first).unwrap();
+        stack.push(snapshot(&[3])).unwrap();
+
+        let mut second = snapshot(&[4, 5]);
+        second.insert_name("wip");
+        let err = stack.push(second.clone()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        let err = stack.append(second.clone()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(stack.iter().count(), 2);
+
+        let path = stack.replace(second.clone()).unwrap();
+        assert!(!first_path.exists());
+        assert_eq!(stack.find_named("wip"), Some(path.clone()));
+        assert_eq!(Snapshot::load(&path).unwrap(), second);
+    }
+
+    #[test]
+    fn names_on_unchanged_refs() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+
+        // Naming unchanged refs records a new snapshot rather than renaming the latest
+        let unnamed = stack.push(snapshot(&[1, 2])).unwrap();
+        let mut named = snapshot(&[1, 2]);
+        named.insert_name("wip");
+        let named_path = stack.push(named.clone()).unwrap();
+        assert_ne!(named_path, unnamed);
+        assert_eq!(Snapshot::load(&unnamed).unwrap().name(), None);
+        assert_eq!(stack.find_named("wip"), Some(named_path.clone()));
+
+        // Pushing the latest snapshot again under its own name is a no-op
+        assert_eq!(stack.push(named.clone()).unwrap(), named_path);
+        assert_eq!(stack.iter().count(), 2);
+
+        // Unnamed pushes of the same refs still fold into it, keeping the name
+        assert_eq!(stack.push(snapshot(&[1, 2])).unwrap(), named_path);
+        assert_eq!(stack.find_named("wip"), Some(named_path));
+        assert_eq!(stack.iter().count(), 2);
+    }
+
+    #[test]
+    fn pins_survive_eviction() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+        stack.capacity(Some(2));
+
+        let pinned = stack.push(snapshot(&[1, 2, 3, 4])).unwrap();
+        let dependent = stack.push(snapshot(&[1, 2, 3, 5])).unwrap();
+        stack.set_pinned(&pinned, true).unwrap();
+        assert!(!Snapshot::load(&dependent).unwrap().is_pinned());
+        for i in 0..5 {
+            stack.push(snapshot(&[1, 2, 3, 10 + i])).unwrap();
+        }
+        assert!(pinned.exists());
+        assert!(!dependent.exists());
+        assert_eq!(stack.iter().count(), 3);
+        assert!(root.path().join(Stack::PINS).exists());
+
+        stack.pins_count(true);
+        stack.push(snapshot(&[1, 2, 3, 20])).unwrap();
+        assert!(pinned.exists());
+        assert_eq!(stack.iter().count(), 2);
+
+        stack.clear().unwrap();
+        assert_eq!(stack.iter().collect::<Vec<_>>(), vec![pinned.clone()]);
+        stack.set_pinned(&pinned, false).unwrap();
+        stack.push(snapshot(&[1, 2, 3, 30])).unwrap();
+        stack.push(snapshot(&[1, 2, 3, 31])).unwrap();
+        assert!(!pinned.exists());
+        stack.clear_all().unwrap();
+        assert!(!root.path().join(Stack::PINS).exists());
+    }
+
+    #[test]
+    fn renumber_closes_gaps() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+
+        let expected: Vec<_> = (0..5).map(|i| snapshot(&[1, 2, 3, 10 + i])).collect();
+        let paths: Vec<_> = expected
+            .iter()
+            .map(|s| stack.push(s.clone()).unwrap())
+            .collect();
+        stack.forget_snapshot(&paths[0]).unwrap();
+        stack.forget_snapshot(&paths[2]).unwrap();
+
+        assert_eq!(stack.renumber().unwrap(), 3);
+        let indexes: Vec<_> = stack.iter().map(|p| index(&p).unwrap()).collect();
+        assert_eq!(indexes, [0, 1, 2]);
+        let actual: Vec<_> = stack.iter().map(|p| Snapshot::load(&p).unwrap()).collect();
+        assert_eq!(
+            actual,
+            [
+                expected[1].clone(),
+                expected[3].clone(),
+                expected[4].clone()
+            ]
+        );
+        assert_eq!(stack.renumber().unwrap(), 0);
+    }
+
+    #[test]
+    fn trash_and_restore() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path().join("test"))
+            .with_trash(root.path().join("trash"));
+        stack.trash_expiry(Some(std::time::Duration::from_secs(60)));
+
+        let keyframe = snapshot(&[1, 2, 3, 4]);
+        let dependent = snapshot(&[1, 2, 3, 5]);
+        let keyframe_path = stack.push(keyframe.clone()).unwrap();
+        stack.push(dependent.clone()).unwrap();
+        stack.remove_snapshot(&keyframe_path).unwrap();
+
+        let trash = stack.trash().unwrap();
+        let trashed: Vec<_> = trash.iter().collect();
+        assert_eq!(trashed.len(), 1);
+        assert!(Snapshot::load(&trashed[0]).unwrap().trashed().is_some());
+
+        // Restoring appends rather than folding into the latest snapshot
+        let restored = stack.restore(&trashed[0]).unwrap();
+        assert_eq!(Snapshot::load(&restored).unwrap(), keyframe);
+        assert_eq!(trash.iter().count(), 0);
+        let dependent_path = stack.iter().next().unwrap();
+        stack.remove_snapshot(&dependent_path).unwrap();
+        stack.push(dependent.clone()).unwrap();
+        let trashed: Vec<_> = trash.iter().collect();
+        stack.restore(&trashed[0]).unwrap();
+        assert_eq!(stack.iter().count(), 3);
+
+        stack.clear_all().unwrap();
+        assert_eq!(stack.iter().count(), 0);
+        assert_eq!(trash.iter().count(), 3);
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(120);
+        assert_eq!(stack.purge_trash(later).unwrap(), 3);
+    }
+
+    #[test]
+    fn nested_stacks() {
+        Stack::check_name("team/alice").unwrap();
+        for name in [
+            "",
+            "../../hooks",
+            "team/../x",
+            ".trash",
+            "team/",
+            "a.lock",
+            "a@{1}",
+        ] {
+            assert!(Stack::check_name(name).is_err(), "{}", name);
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        let store = crate::Store::new(root.path());
+        assert!(store.stack("../../hooks").is_err());
+        store.stack("team").unwrap().push(snapshot(&[1])).unwrap();
+        store
+            .stack("team/alice")
+            .unwrap()
+            .push(snapshot(&[2]))
+            .unwrap();
+        store.stack("team-b").unwrap().push(snapshot(&[3])).unwrap();
+        let names: Vec<_> = store.stacks().map(|s| s.name).collect();
+        assert_eq!(names, ["recent", "team", "team/alice", "team-b"]);
+
+        store.delete_stack("team").unwrap();
+        assert_eq!(store.stack("team/alice").unwrap().iter().count(), 1);
+        store.rename_stack("team/alice", "alice").unwrap();
+        let names: Vec<_> = store.stacks().map(|s| s.name).collect();
+        assert_eq!(names, ["alice", "recent", "team-b"]);
+        assert!(!root.path().join("team").exists());
+        let trash: Vec<_> = store.trash().map(|s| s.name).collect();
+        assert_eq!(trash, ["team"]);
+    }
+
+    #[test]
+    fn locked_stack() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path().join("test"));
+
+        let lock = stack.lock().unwrap();
+        let err = stack.push(snapshot(&[1])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("test.lock"), "{}", err);
+        drop(lock);
+
+        stack.push(snapshot(&[1])).unwrap();
+        stack.push(snapshot(&[2])).unwrap();
+        let mut files: Vec<_> = std::fs::read_dir(root.path())
+            .unwrap()
+            .chain(std::fs::read_dir(root.path().join("test")).unwrap())
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["0.bak", "1.bak", "test"]);
+
+        let lock = stack.lock().unwrap();
+        assert!(stack.pop().is_err());
+        drop(lock);
+        assert!(stack.pop().unwrap().is_some());
+    }
+
+    #[test]
+    fn deltas_survive_eviction() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+        stack.capacity(Some(5));
+
+        let mut expected = Vec::new();
+        for i in 0..25 {
+            let snapshot = snapshot(&[1, 2, 3, 4, 100 + i]);
+            stack.push(snapshot.clone()).unwrap();
+            expected.push(snapshot);
+        }
+
+        let paths: Vec<_> = stack.iter().collect();
+        assert!(paths.iter().any(|p| matches!(delta_base(p), Ok(Some(_)))));
+        let actual: Vec<_> = paths.iter().map(|p| Snapshot::load(p).unwrap()).collect();
+        assert_eq!(actual, expected[expected.len() - actual.len()..]);
+
+        while let Some(path) = stack.pop().unwrap() {
+            assert!(!path.exists());
+            for p in stack.iter() {
+                Snapshot::load(&p).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn unreadable_snapshots() {
+        let root = tempfile::tempdir().unwrap();
+        let mut stack = Stack::with_root("test", root.path());
+        stack.capacity(Some(3));
+
+        let first = stack.push(snapshot(&[1, 2, 3, 4])).unwrap();
+        let corrupt = stack.push(snapshot(&[1, 2, 3, 5])).unwrap();
+        stack.push(snapshot(&[1, 2, 3, 6])).unwrap();
+        std::fs::write(&corrupt, "{").unwrap();
+
+        stack.push(snapshot(&[1, 2, 3, 7])).unwrap();
+        assert!(!first.exists());
+        for path in stack.iter().filter(|p| p != &corrupt) {
+            Snapshot::load(&path).unwrap();
+        }
+
+        let err = stack.set_pinned(&corrupt, true).unwrap_err();
+        assert!(err.to_string().contains("1.bak"), "{}", err);
+    }
 }
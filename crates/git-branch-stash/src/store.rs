@@ -0,0 +1,674 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Store {
+    root: std::path::PathBuf,
+}
+
+impl Store {
+    const MANIFEST: &'static str = "repo.json";
+    const TRASH: &'static str = ".trash";
+    const QUARANTINE: &'static str = ".quarantine";
+
+    pub fn new(root: impl Into<std::path::PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn in_repo(repo: &crate::git::GitRepo) -> Self {
+        Self::new(common_dir(repo.raw()).join("branch-stash"))
+    }
+
+    pub fn in_worktree(repo: &crate::git::GitRepo) -> Self {
+        Self::new(repo.raw().path().join("branch-stash"))
+    }
+
+    pub fn global(id: &str) -> eyre::Result<Self> {
+        // The id names one directory under the data directory
+        let mut components = std::path::Path::new(id).components();
+        let single = matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        );
+        if !single || id.contains(|c| c == '/' || c == '\\') {
+            eyre::bail!("`{}` is not a valid store id", id);
+        }
+        let root = global_root()
+            .ok_or_else(|| eyre::eyre!("cannot find a data directory for snapshots"))?;
+        Ok(Self::new(root.join(id)))
+    }
+
+    pub fn from_config(
+        repo: &crate::git::GitRepo,
+        config: &crate::config::RepoConfig,
+    ) -> eyre::Result<Self> {
+        match config.store() {
+            crate::config::StoreLocation::Repo if config.per_worktree() => {
+                Ok(Self::in_worktree(repo))
+            }
+            crate::config::StoreLocation::Repo => {
+                let store = Self::in_repo(repo);
+                store.adopt(&Self::in_worktree(repo))?;
+                Ok(store)
+            }
+            crate::config::StoreLocation::Global => {
+                let store = Self::global(&store_id(repo.raw(), config)?)?;
+                // Snapshots from before the switch to the global store come along
+                store.adopt(&Self::in_repo(repo))?;
+                store.adopt(&Self::in_worktree(repo))?;
+                store.record(repo.raw())?;
+                Ok(store)
+            }
+        }
+    }
+
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
+    }
+
+    pub fn stack(&self, name: &str) -> eyre::Result<crate::Stack> {
+        // The name is joined onto the store root, so it must not escape it
+        crate::Stack::check_name(name)?;
+        Ok(self.open_stack(name))
+    }
+
+    fn open_stack(&self, name: &str) -> crate::Stack {
+        crate::Stack::with_root(name, self.root.join(name))
+            .with_trash(self.root.join(Self::TRASH).join(name))
+    }
+
+    pub fn stacks(&self) -> impl Iterator<Item = crate::Stack> {
+        let mut stacks: Vec<_> = stack_names(&self.root)
+            .into_iter()
+            .map(|name| self.open_stack(&name))
+            .collect();
+        if !stacks.iter().any(|v| v.name == crate::Stack::DEFAULT_STACK) {
+            stacks.push(self.open_stack(crate::Stack::DEFAULT_STACK));
+        }
+        stacks.sort_unstable_by(|a, b| a.name.split('/').cmp(b.name.split('/')));
+        stacks.into_iter()
+    }
+
+    pub fn trash(&self) -> impl Iterator<Item = crate::Stack> {
+        let trash_root = self.root.join(Self::TRASH);
+        let mut stacks: Vec<_> = stack_names(&trash_root)
+            .into_iter()
+            .map(|name| crate::Stack::with_root(&name, trash_root.join(&name)))
+            .collect();
+        stacks.sort_unstable_by(|a, b| a.name.split('/').cmp(b.name.split('/')));
+        stacks.into_iter()
+    }
+
+    pub fn rename_stack(&self, from: &str, to: &str) -> eyre::Result<()> {
+        let source = self.existing_stack(from, to)?;
+        let source_lock = source.lock()?;
+        let mut target = self.open_stack(to);
+        let _target_lock = target.lock()?;
+        if target.iter().next().is_none() {
+            // Move file by file so nested stacks stay where they are
+            let target_root = self.root.join(to);
+            std::fs::create_dir_all(&target_root)?;
+            for path in source.iter() {
+                std::fs::rename(&path, target_root.join(path.file_name().unwrap()))?;
+            }
+        } else {
+            append_locked(&source, &mut target)?;
+            for path in source.iter() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        self.move_trash(from, to)?;
+        // The lock file lives next to the stack, keeping its parent alive
+        drop(source_lock);
+        remove_empty_dirs(&self.root, from);
+        Ok(())
+    }
+
+    pub fn copy_stack(&self, from: &str, to: &str) -> eyre::Result<()> {
+        let source = self.existing_stack(from, to)?;
+        let _source_lock = source.lock()?;
+        let mut target = self.open_stack(to);
+        let _target_lock = target.lock()?;
+        if target.iter().next().is_none() {
+            // Deltas refer to their keyframe by index, so keep the numbering
+            let target_root = self.root.join(to);
+            std::fs::create_dir_all(&target_root)?;
+            for path in source.iter() {
+                let data = std::fs::read(&path)?;
+                crate::fs::write_atomic(&target_root.join(path.file_name().unwrap()), &data)?;
+            }
+        } else {
+            append_locked(&source, &mut target)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_stack(&self, name: &str) -> eyre::Result<()> {
+        crate::Stack::check_name(name)?;
+        let root = self.root.join(name);
+        if !root.is_dir() {
+            eyre::bail!("stack `{}` does not exist", name);
+        }
+        self.open_stack(name).clear_all()?;
+        remove_empty_dirs(&self.root, name);
+        Ok(())
+    }
+
+    // Set aside a file from `stack` that can't be used, without losing it
+    pub(crate) fn quarantine(
+        &self,
+        stack: &str,
+        path: &std::path::Path,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let root = self.root.join(Self::QUARANTINE).join(stack);
+        std::fs::create_dir_all(&root)?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut destination = root.join(file_name.as_ref());
+        let mut n = 1;
+        while destination.exists() {
+            destination = root.join(format!("{}.{}", file_name, n));
+            n += 1;
+        }
+        std::fs::rename(path, &destination)?;
+        Ok(destination)
+    }
+
+    fn move_trash(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        let trash_root = self.root.join(Self::TRASH);
+        move_trash(
+            &crate::Stack::with_root(from, trash_root.join(from)),
+            &crate::Stack::with_root(to, trash_root.join(to)),
+        )?;
+        remove_empty_dirs(&trash_root, from);
+        Ok(())
+    }
+
+    // Move the stacks of `other` onto the end of ours, like those a worktree kept before stacks
+    // were shared across worktrees, or the repository's before it used the global store
+    fn adopt(&self, other: &Store) -> eyre::Result<()> {
+        if other.root == self.root || !other.root.is_dir() {
+            return Ok(());
+        }
+        for source in other.stacks() {
+            let source_lock = source.lock()?;
+            let paths: Vec<_> = source.iter().collect();
+            if !paths.is_empty() {
+                let mut target = self.open_stack(&source.name);
+                let _target_lock = target.lock()?;
+                append_locked(&source, &mut target)?;
+                for path in paths.iter() {
+                    std::fs::remove_file(path)?;
+                }
+                log::info!(
+                    "Moved {} snapshots from {} into the `{}` stack in {}",
+                    paths.len(),
+                    source.root().display(),
+                    source.name,
+                    self.root.display()
+                );
+            }
+            drop(source_lock);
+            remove_empty_dirs(&other.root, &source.name);
+        }
+        let other_trash = other.root.join(Self::TRASH);
+        for trash in other.trash() {
+            let target = self.root.join(Self::TRASH).join(&trash.name);
+            move_trash(&trash, &crate::Stack::with_root(&trash.name, target))?;
+            remove_empty_dirs(&other_trash, &trash.name);
+        }
+        let _ = std::fs::remove_dir(&other_trash);
+        let _ = std::fs::remove_dir(&other.root);
+        Ok(())
+    }
+
+    fn existing_stack(&self, from: &str, to: &str) -> eyre::Result<crate::Stack> {
+        crate::Stack::check_name(from)?;
+        crate::Stack::check_name(to)?;
+        if from == to {
+            eyre::bail!("stack `{}` can't be copied onto itself", from);
+        }
+        let source = self.open_stack(from);
+        if source.iter().next().is_none() {
+            eyre::bail!("stack `{}` has no snapshots", from);
+        }
+        Ok(source)
+    }
+
+    pub fn manifest(&self) -> Option<Manifest> {
+        let path = self.root.join(Self::MANIFEST);
+        let manifest = std::fs::read(&path).ok()?;
+        serde_json::from_slice(&manifest).ok()
+    }
+
+    pub fn record(&self, repo: &git2::Repository) -> eyre::Result<()> {
+        let existing = self.manifest();
+        let mut manifest = existing.clone().unwrap_or_default();
+        let current = Manifest::from_repo(repo);
+        manifest.root = current.root.or(manifest.root);
+        manifest.remotes = current.remotes;
+        for workdir in current.workdirs {
+            if !manifest.workdirs.contains(&workdir) {
+                manifest.workdirs.push(workdir);
+            }
+        }
+        if existing.as_ref() == Some(&manifest) {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&self.root)?;
+        let path = self.root.join(Self::MANIFEST);
+        crate::fs::write_atomic(&path, serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn is_orphaned(&self) -> bool {
+        let id = match self.root.file_name().and_then(|n| n.to_str()) {
+            Some(id) => id,
+            None => return true,
+        };
+        let manifest = self.manifest().unwrap_or_default();
+        !manifest.workdirs.iter().any(|workdir| {
+            let repo = match git2::Repository::open(workdir) {
+                Ok(repo) => repo,
+                Err(_) => return false,
+            };
+            let config = crate::config::RepoConfig::from_repo(&repo).unwrap_or_default();
+            let repo_id = config.store_id.or_else(|| repo_id(&repo));
+            repo_id.as_deref() == Some(id)
+        })
+    }
+}
+
+// Any directory holding snapshots is a stack, named by its path relative to `root`
+fn stack_names(root: &std::path::Path) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut pending = vec![(root.to_owned(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for e in std::fs::read_dir(&dir).into_iter().flatten() {
+            let e = match e {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            if !e.file_type().map_or(false, |t| t.is_dir()) {
+                continue;
+            }
+            let name = match e.file_name().to_str() {
+                // Not a valid stack name, see `TRASH`
+                Some(name) if !name.starts_with('.') => format!("{}{}", prefix, name),
+                _ => continue,
+            };
+            let has_snapshots = crate::Stack::with_root(&name, e.path())
+                .iter()
+                .next()
+                .is_some();
+            if has_snapshots && crate::Stack::check_name(&name).is_ok() {
+                names.push(name.clone());
+            }
+            pending.push((e.path(), format!("{}/", name)));
+        }
+    }
+    names
+}
+
+// Renumber onto the end of an existing stack, with both stacks' locks held
+fn append_locked(source: &crate::Stack, target: &mut crate::Stack) -> eyre::Result<()> {
+    for path in source.iter() {
+        let snapshot = crate::Snapshot::load(&path)?;
+        target.check_unique(&snapshot)?;
+        target.append_locked(&snapshot)?;
+    }
+    Ok(())
+}
+
+// Trashed snapshots are always saved in full, so they can be renumbered freely
+fn move_trash(source: &crate::Stack, target: &crate::Stack) -> Result<(), std::io::Error> {
+    let next_index = target
+        .iter()
+        .last()
+        .and_then(|p| crate::stack::index(&p))
+        .map_or(0, |i| i + 1);
+    for (i, path) in source.iter().enumerate() {
+        std::fs::create_dir_all(target.root())?;
+        let new_path = target
+            .root()
+            .join(format!("{}.{}", next_index + i, crate::Stack::EXT));
+        std::fs::rename(&path, &new_path)?;
+        log::trace!("Trashed {} as {}", path.display(), new_path.display());
+    }
+    Ok(())
+}
+
+// Clean up after `name` and any of its parents that only held it
+fn remove_empty_dirs(root: &std::path::Path, name: &str) {
+    let mut name = Some(name);
+    while let Some(current) = name {
+        let _ = std::fs::remove_file(root.join(current).join(crate::Stack::PINS));
+        if std::fs::remove_dir(root.join(current)).is_err() {
+            break;
+        }
+        name = current.rsplit_once('/').map(|(parent, _)| parent);
+    }
+}
+
+pub fn global_stores() -> impl Iterator<Item = (String, Store)> {
+    let mut stores: Vec<_> = global_root()
+        .and_then(|root| std::fs::read_dir(root).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|e| {
+            let e = e.ok()?;
+            let e = e.file_type().ok()?.is_dir().then(|| e)?;
+            let id = e.file_name().to_str()?.to_owned();
+            Some((id, Store::new(e.path())))
+        })
+        .collect();
+    stores.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    stores.into_iter()
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub root: Option<String>,
+    #[serde(default)]
+    pub remotes: Vec<String>,
+    #[serde(default)]
+    pub workdirs: Vec<std::path::PathBuf>,
+}
+
+impl Manifest {
+    pub fn from_repo(repo: &git2::Repository) -> Self {
+        let root = root_commit(repo).map(|id| id.to_string());
+        let remotes = remote_urls(repo);
+        let workdirs = repo
+            .workdir()
+            .or_else(|| Some(repo.path()))
+            .and_then(|p| p.canonicalize().ok())
+            .into_iter()
+            .collect();
+        Self {
+            root,
+            remotes,
+            workdirs,
+        }
+    }
+}
+
+// The configured store id, otherwise one derived from the repository and saved to its config so
+// later changes to the remotes don't move the store
+fn store_id(repo: &git2::Repository, config: &crate::config::RepoConfig) -> eyre::Result<String> {
+    if let Some(id) = config.store_id.as_deref() {
+        return Ok(id.to_owned());
+    }
+    let id =
+        repo_id(repo).ok_or_else(|| eyre::eyre!("cannot identify repository without commits"))?;
+    let persisted = crate::config::RepoConfig {
+        store_id: Some(id.clone()),
+        ..Default::default()
+    };
+    persisted.write_repo(repo)?;
+    Ok(id)
+}
+
+pub fn repo_id(repo: &git2::Repository) -> Option<String> {
+    let root = root_commit(repo)?;
+    let mut identity = root.to_string();
+    for url in remote_urls(repo) {
+        identity.push('\n');
+        identity.push_str(&url);
+    }
+    let id = git2::Oid::hash_object(git2::ObjectType::Blob, identity.as_bytes()).ok()?;
+    Some(id.to_string())
+}
+
+pub(crate) fn common_dir(repo: &git2::Repository) -> std::path::PathBuf {
+    // Linked worktrees point to the main repository's git dir
+    let path = repo.path();
+    match std::fs::read_to_string(path.join("commondir")) {
+        Ok(common_dir) => {
+            let common_dir = path.join(common_dir.trim());
+            common_dir.canonicalize().unwrap_or(common_dir)
+        }
+        Err(_) => path.to_owned(),
+    }
+}
+
+fn root_commit(repo: &git2::Repository) -> Option<git2::Oid> {
+    let mut walk = repo.revwalk().ok()?;
+    walk.push_head().ok()?;
+    walk.simplify_first_parent().ok()?;
+    walk.filter_map(Result::ok).last()
+}
+
+fn remote_urls(repo: &git2::Repository) -> Vec<String> {
+    let mut urls: Vec<_> = repo
+        .remotes()
+        .into_iter()
+        .flat_map(|names| {
+            names
+                .iter()
+                .flatten()
+                .filter_map(|name| repo.find_remote(name).ok()?.url().map(|u| u.to_owned()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    urls.sort_unstable();
+    urls.dedup();
+    urls
+}
+
+fn global_root() -> Option<std::path::PathBuf> {
+    let data_dir = if let Some(data_home) = std::env::var_os("XDG_DATA_HOME") {
+        std::path::PathBuf::from(data_home)
+    } else if let Some(local_app_data) = std::env::var_os("LOCALAPPDATA") {
+        std::path::PathBuf::from(local_app_data)
+    } else {
+        std::path::PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("share")
+    };
+    Some(data_dir.join("git-branch-stash"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{oid, with_message};
+
+    fn snapshot(message: &str, id: u32) -> crate::Snapshot {
+        with_message(crate::testing::snapshot(&[("main", oid(id))]), message)
+    }
+
+    #[test]
+    fn rename_and_copy() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let mut wip = store.stack("wip").unwrap();
+        wip.push(snapshot("a", 1)).unwrap();
+        wip.push(snapshot("b", 2)).unwrap();
+        let trashed = wip.push(snapshot("c", 3)).unwrap();
+        wip.remove_snapshot(&trashed).unwrap();
+        let mut keep = store.stack("keep").unwrap();
+        keep.push(snapshot("b", 2)).unwrap();
+
+        // Appending keeps equal snapshots apart rather than merging their messages
+        store.copy_stack("wip", "keep").unwrap();
+        let messages: Vec<_> = keep
+            .iter()
+            .map(|p| {
+                crate::Snapshot::load(&p)
+                    .unwrap()
+                    .message()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+        assert_eq!(messages, ["b", "a", "b"]);
+
+        store.rename_stack("wip", "old").unwrap();
+        assert!(!root.path().join("wip").exists());
+        assert!(!root.path().join(".trash/wip").exists());
+        assert_eq!(store.stack("old").unwrap().iter().count(), 2);
+        let trash: Vec<_> = store
+            .stack("old")
+            .unwrap()
+            .trash()
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(
+            crate::Snapshot::load(&trash[0]).unwrap().message(),
+            Some("c")
+        );
+    }
+
+    fn repo(root: &std::path::Path) -> git2::Repository {
+        let repo = git2::Repository::init(root).unwrap();
+        let id = crate::testing::git_commit(&repo, None, ("base", "base"), "base");
+        repo.branch("main", &repo.find_commit(id).unwrap(), false)
+            .unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        repo.remote("origin", "https://example.com/one.git")
+            .unwrap();
+        repo
+    }
+
+    #[test]
+    fn global_ids() {
+        for id in ["", ".", "..", "../hooks", "a/b", "a\\b", "/tmp"] {
+            assert!(Store::global(id).is_err(), "{:?}", id);
+        }
+        if global_root().is_some() {
+            assert!(Store::global("abc").unwrap().root().ends_with("abc"));
+        }
+    }
+
+    #[test]
+    fn store_id_is_persisted() {
+        let root = tempfile::tempdir().unwrap();
+        let repo = repo(root.path());
+        let config = crate::config::RepoConfig::from_repo(&repo).unwrap();
+        let id = store_id(&repo, &config).unwrap();
+
+        repo.remote_set_url("origin", "https://example.com/two.git")
+            .unwrap();
+        assert_ne!(repo_id(&repo), Some(id.clone()));
+        let config = crate::config::RepoConfig::from_repo(&repo).unwrap();
+        assert_eq!(store_id(&repo, &config).unwrap(), id);
+    }
+
+    #[test]
+    fn record_only_on_change() {
+        let root = tempfile::tempdir().unwrap();
+        let repo = repo(&root.path().join("repo"));
+        let store = Store::new(root.path().join("store"));
+        let manifest_path = store.root().join(Store::MANIFEST);
+
+        store.record(&repo).unwrap();
+        let manifest = store.manifest().unwrap();
+        assert_eq!(manifest.remotes, ["https://example.com/one.git"]);
+
+        // Anything but the pretty-printed form shows whether it was rewritten
+        let compact = serde_json::to_string(&manifest).unwrap();
+        std::fs::write(&manifest_path, &compact).unwrap();
+        store.record(&repo).unwrap();
+        assert_eq!(std::fs::read_to_string(&manifest_path).unwrap(), compact);
+
+        repo.remote_set_url("origin", "https://example.com/two.git")
+            .unwrap();
+        store.record(&repo).unwrap();
+        assert_eq!(
+            store.manifest().unwrap().remotes,
+            ["https://example.com/two.git"]
+        );
+    }
+
+    #[test]
+    fn worktrees() {
+        let root = tempfile::tempdir().unwrap();
+        let main = repo(&root.path().join("main"));
+        main.worktree("wt", &root.path().join("wt"), None).unwrap();
+        let main = crate::git::GitRepo::new(main);
+        let wt = crate::git::GitRepo::new(git2::Repository::open(root.path().join("wt")).unwrap());
+
+        let shared = crate::config::RepoConfig::default();
+        let per_worktree = crate::config::RepoConfig {
+            per_worktree: Some(true),
+            ..Default::default()
+        };
+        let main_store = Store::from_config(&main, &shared).unwrap();
+        assert_eq!(Store::from_config(&wt, &shared).unwrap(), main_store);
+        let wt_store = Store::from_config(&wt, &per_worktree).unwrap();
+        assert_ne!(wt_store, main_store);
+        assert!(wt_store.root().starts_with(wt.raw().path()));
+
+        // Stacks a worktree kept before they were shared are moved over
+        main_store
+            .stack("recent")
+            .unwrap()
+            .push(snapshot("main", 1))
+            .unwrap();
+        let mut legacy = wt_store.stack("recent").unwrap();
+        legacy.push(snapshot("wt", 2)).unwrap();
+        let trashed = legacy.push(snapshot("trashed", 3)).unwrap();
+        legacy.remove_snapshot(&trashed).unwrap();
+        Store::from_config(&wt, &shared).unwrap();
+        assert!(!wt_store.root().exists());
+        let messages: Vec<_> = main_store
+            .stack("recent")
+            .unwrap()
+            .iter()
+            .map(|p| {
+                crate::Snapshot::load(&p)
+                    .unwrap()
+                    .message()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+        assert_eq!(messages, ["main", "wt"]);
+        assert_eq!(
+            main_store
+                .stack("recent")
+                .unwrap()
+                .trash()
+                .unwrap()
+                .iter()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn adopt_repo_store() {
+        let root = tempfile::tempdir().unwrap();
+        let repo = crate::git::GitRepo::new(repo(&root.path().join("repo")));
+        let in_repo = Store::in_repo(&repo);
+        in_repo
+            .stack("recent")
+            .unwrap()
+            .push(snapshot("repo", 1))
+            .unwrap();
+
+        // Like switching to `branch-stash.store=global`
+        let global = Store::new(root.path().join("global"));
+        global
+            .stack("recent")
+            .unwrap()
+            .push(snapshot("global", 2))
+            .unwrap();
+        global.adopt(&in_repo).unwrap();
+        assert!(!in_repo.root().exists());
+        let messages: Vec<_> = global
+            .stack("recent")
+            .unwrap()
+            .iter()
+            .map(|p| {
+                crate::Snapshot::load(&p)
+                    .unwrap()
+                    .message()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+        assert_eq!(messages, ["global", "repo"]);
+    }
+}
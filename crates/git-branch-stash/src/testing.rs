@@ -0,0 +1,105 @@
+pub fn oid(n: u32) -> git2::Oid {
+    git2::Oid::from_str(&format!("{:040x}", n)).unwrap()
+}
+
+pub fn branch(name: &str, id: git2::Oid) -> crate::Branch {
+    crate::Branch {
+        name: name.to_owned(),
+        id,
+        metadata: Default::default(),
+        extra: Default::default(),
+    }
+}
+
+pub fn snapshot(branches: &[(&str, git2::Oid)]) -> crate::Snapshot {
+    crate::Snapshot {
+        version: crate::Snapshot::VERSION,
+        branches: branches
+            .iter()
+            .map(|(name, id)| branch(name, *id))
+            .collect(),
+        metadata: Default::default(),
+        extra: Default::default(),
+    }
+}
+
+/// Snapshot with branches named `b0`, `b1`, ... pointing at `ids`
+pub fn numbered(ids: &[git2::Oid]) -> crate::Snapshot {
+    let names: Vec<_> = (0..ids.len()).map(|i| format!("b{}", i)).collect();
+    let branches: Vec<_> = names
+        .iter()
+        .zip(ids)
+        .map(|(name, id)| (name.as_str(), *id))
+        .collect();
+    snapshot(&branches)
+}
+
+pub fn with_parent(mut snapshot: crate::Snapshot, name: &str, parent: &str) -> crate::Snapshot {
+    let branch = snapshot
+        .branches
+        .iter_mut()
+        .find(|b| b.name == name)
+        .unwrap();
+    branch.metadata.insert(
+        "parent".to_owned(),
+        serde_json::Value::String(parent.to_owned()),
+    );
+    snapshot
+}
+
+pub fn with_message(mut snapshot: crate::Snapshot, message: &str) -> crate::Snapshot {
+    snapshot.insert_message(message);
+    snapshot
+}
+
+pub fn commit(
+    repo: &mut crate::git::InMemoryRepo,
+    parent: Option<git2::Oid>,
+    summary: &str,
+) -> git2::Oid {
+    let id = repo.gen_id();
+    repo.push_commit(
+        parent,
+        crate::git::Commit {
+            id,
+            tree_id: id,
+            summary: summary.into(),
+            time: std::time::UNIX_EPOCH,
+            author: None,
+            committer: None,
+        },
+    );
+    id
+}
+
+/// Commit `file` on top of `parent` without moving any refs
+pub fn git_commit(
+    repo: &git2::Repository,
+    parent: Option<git2::Oid>,
+    file: (&str, &str),
+    message: &str,
+) -> git2::Oid {
+    let parent = parent.map(|id| repo.find_commit(id).unwrap());
+    let parent_tree = parent.as_ref().map(|p| p.tree().unwrap());
+    let mut tree = repo.treebuilder(parent_tree.as_ref()).unwrap();
+    let blob = repo.blob(file.1.as_bytes()).unwrap();
+    tree.insert(file.0, blob, 0o100644).unwrap();
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+    let sig = git2::Signature::now("test", "test@example.com").unwrap();
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(None, &sig, &sig, message, &tree, &parents)
+        .unwrap()
+}
+
+/// Merge `parents`, keeping the first parent's tree
+pub fn git_merge(repo: &git2::Repository, parents: &[git2::Oid], message: &str) -> git2::Oid {
+    let parents: Vec<_> = parents
+        .iter()
+        .map(|id| repo.find_commit(*id).unwrap())
+        .collect();
+    let tree = parents[0].tree().unwrap();
+    let sig = git2::Signature::now("test", "test@example.com").unwrap();
+    let parents: Vec<_> = parents.iter().collect();
+    repo.commit(None, &sig, &sig, message, &tree, &parents)
+        .unwrap()
+}
@@ -12,6 +12,10 @@ pub struct Args {
     #[clap(flatten)]
     pub push: PushArgs,
 
+    /// Write the current configuration to file with `-` for stdout
+    #[clap(long, parse(from_os_str), value_name = "PATH")]
+    pub dump_config: Option<std::path::PathBuf>,
+
     #[clap(flatten)]
     pub(crate) color: concolor_clap::Color,
 
@@ -25,60 +29,336 @@ pub enum Subcommand {
     Push(PushArgs),
     /// List all stashed snapshots
     List(ListArgs),
+    /// Show a snapshot's details and the commits on each branch
+    Show(ShowArgs),
+    /// Compare a snapshot to the current branches or to another snapshot
+    Diff(DiffArgs),
+    /// Compare a branch's commits in a snapshot to the branch now
+    RangeDiff(RangeDiffArgs),
+    /// List commits that only a snapshot still reaches
+    Lost(LostArgs),
+    /// Show where a branch pointed across snapshots
+    History(HistoryArgs),
     /// Clear all snapshots
     Clear(ClearArgs),
-    /// Delete the last snapshot
+    /// Delete a snapshot, the last by default
     Drop(DropArgs),
-    /// Apply the last snapshot, deleting it
+    /// Apply a snapshot, deleting it
     Pop(ApplyArgs),
-    /// Apply the last snapshot
+    /// Apply a snapshot, the last by default
     Apply(ApplyArgs),
+    /// Exempt a snapshot from capacity eviction and `clear`
+    Pin(PinArgs),
+    /// Allow a snapshot to be evicted again
+    Unpin(PinArgs),
+    /// Delete snapshots past the `branch-stash.keep-*` retention rules
+    Prune(PruneArgs),
     /// List all snapshot stacks
     Stacks(StacksArgs),
+    /// Rename, copy or delete a snapshot stack
+    #[clap(subcommand)]
+    Stack(StackCommand),
+    /// List or restore removed snapshots
+    #[clap(subcommand)]
+    Trash(TrashCommand),
+    /// Move a snapshot onto the end of another stack
+    Move(TransferArgs),
+    /// Copy a snapshot onto the end of another stack
+    Copy(TransferArgs),
+    /// Write a snapshot and its commits to a git bundle
+    Export(ExportArgs),
+    /// Push the snapshot from a git bundle, unpacking its commits
+    Import(ImportArgs),
+    /// List snapshot stores kept outside of repositories
+    Stores(StoresArgs),
+    /// Use an existing global snapshot store for this repository
+    Attach(AttachArgs),
+    /// Upgrade snapshots to the current format
+    Migrate(MigrateArgs),
+    /// Print the JSON Schema for snapshot files
+    Schema(SchemaArgs),
+    /// Check snapshot files against the JSON Schema
+    Validate(ValidateArgs),
+    /// Check every stack for corrupt snapshots, missing commits and stray files
+    Fsck(FsckArgs),
 }
 
 #[derive(clap::Args)]
 pub struct PushArgs {
     /// Specify which stash stack to use
-    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
     pub stack: String,
 
     /// Annotate the snapshot with the given message
     #[clap(short, long)]
     pub message: Option<String>,
+
+    /// Name the snapshot, for use as `<stack>@{<name>}`
+    ///
+    /// Always records a new snapshot, even when the branches haven't changed since the last one
+    #[clap(short, long)]
+    pub name: Option<String>,
+
+    /// Replace an existing snapshot with the same name
+    #[clap(short, long)]
+    pub force: bool,
 }
 
 #[derive(clap::Args)]
 pub struct ListArgs {
     /// Specify which stash stack to use
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
+    pub stack: String,
+}
+
+#[derive(clap::Args)]
+pub struct ShowArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Show the files changed by each commit
+    #[clap(long)]
+    pub stat: bool,
+}
+
+#[derive(clap::Args)]
+pub struct DiffArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Snapshot to compare against instead of the current branches
+    pub other: Option<git_branch_stash::SnapshotRef>,
+
+    /// Summarize the commits added, dropped or rewritten on each moved branch
+    #[clap(long)]
+    pub commits: bool,
+
+    /// Print the changes as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args)]
+pub struct RangeDiffArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Branch to compare
+    pub branch: String,
+
+    /// Print the changes as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args)]
+pub struct LostArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
     #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Check every snapshot in every stack
+    #[clap(long, conflicts_with = "snapshot")]
+    pub all: bool,
+
+    /// Create a `lost/<branch>` branch for each branch with lost commits
+    #[clap(long)]
+    pub recreate: bool,
+}
+
+#[derive(clap::Args)]
+pub struct HistoryArgs {
+    /// Branch to trace
+    pub branch: String,
+
+    /// Specify which stash stack to use
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
     pub stack: String,
+
+    /// Trace the branch through every stack
+    #[clap(long, conflicts_with = "stack")]
+    pub all: bool,
 }
 
 #[derive(clap::Args)]
 pub struct ClearArgs {
     /// Specify which stash stack to use
-    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
     pub stack: String,
+
+    /// Also clear pinned snapshots
+    #[clap(long)]
+    pub all: bool,
 }
 
 #[derive(clap::Args)]
 pub struct DropArgs {
-    /// Specify which stash stack to use
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
     #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
-    pub stack: String,
+    pub snapshot: git_branch_stash::SnapshotRef,
 }
 
 #[derive(clap::Args)]
 pub struct ApplyArgs {
-    /// Specify which stash stack to use
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
     #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Fetch missing commits from another repository (path, URL, or remote name)
+    #[clap(long)]
+    pub from: Option<String>,
+
+    /// Don't fetch missing commits from the pull or push remote
+    #[clap(long)]
+    pub offline: bool,
+}
+
+#[derive(clap::Args)]
+pub struct PinArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    pub snapshot: git_branch_stash::SnapshotRef,
+}
+
+#[derive(clap::Args)]
+pub struct PruneArgs {
+    /// Specify which stash stack to use
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
     pub stack: String,
+
+    /// Show what would be deleted without deleting it
+    #[clap(short = 'n', long)]
+    pub dry_run: bool,
 }
 
 #[derive(clap::Args)]
 pub struct StacksArgs {}
 
+#[derive(clap::Subcommand)]
+pub enum StackCommand {
+    /// Rename a stack, appending to the target if it already has snapshots
+    Rename(StackPairArgs),
+    /// Copy a stack, appending to the target if it already has snapshots
+    Copy(StackPairArgs),
+    /// Delete a stack and all of its snapshots
+    Delete(StackDeleteArgs),
+}
+
+#[derive(clap::Args)]
+pub struct StackPairArgs {
+    /// Stack to read from
+    #[clap(validator = git_branch_stash::Stack::check_name)]
+    pub from: String,
+
+    /// Stack to write to
+    #[clap(validator = git_branch_stash::Stack::check_name)]
+    pub to: String,
+}
+
+#[derive(clap::Args)]
+pub struct StackDeleteArgs {
+    /// Stack to delete
+    #[clap(validator = git_branch_stash::Stack::check_name)]
+    pub stack: String,
+}
+
+#[derive(clap::Subcommand)]
+pub enum TrashCommand {
+    /// List removed snapshots, most recently removed first
+    List(TrashListArgs),
+    /// Put a removed snapshot back onto its stack
+    Restore(TrashRestoreArgs),
+}
+
+#[derive(clap::Args)]
+pub struct TrashListArgs {
+    /// Only show the trash for this stack
+    #[clap(validator = git_branch_stash::Stack::check_name)]
+    pub stack: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub struct TrashRestoreArgs {
+    /// Specify which stash stack to use
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
+    pub stack: String,
+
+    /// Entry to restore, as numbered by `trash list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+}
+
+#[derive(clap::Args)]
+pub struct TransferArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Stack to add the snapshot to
+    #[clap(validator = git_branch_stash::Stack::check_name)]
+    pub stack: String,
+}
+
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// Snapshot to use: `<stack>`, `<stack>@{<n>}`, `<stack>@{<n>.<unit>.ago}`, or `<stack>@{<name>}`
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK)]
+    pub snapshot: git_branch_stash::SnapshotRef,
+
+    /// Bundle to write
+    #[clap(short, long, parse(from_os_str))]
+    pub output: std::path::PathBuf,
+}
+
+#[derive(clap::Args)]
+pub struct ImportArgs {
+    /// Bundle to read
+    #[clap(parse(from_os_str))]
+    pub bundle: std::path::PathBuf,
+
+    /// Specify which stash stack to use
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
+    pub stack: String,
+}
+
+#[derive(clap::Args)]
+pub struct StoresArgs {
+    /// Only show stores whose repository no longer exists
+    #[clap(long)]
+    pub orphaned: bool,
+}
+
+#[derive(clap::Args)]
+pub struct AttachArgs {
+    /// ID of the store, as shown by `stores`
+    pub store: String,
+}
+
+#[derive(clap::Args)]
+pub struct MigrateArgs {
+    /// Specify which stash stack to use
+    #[clap(default_value = git_branch_stash::Stack::DEFAULT_STACK, validator = git_branch_stash::Stack::check_name)]
+    pub stack: String,
+}
+
+#[derive(clap::Args)]
+pub struct SchemaArgs {}
+
+#[derive(clap::Args)]
+pub struct ValidateArgs {
+    /// Snapshot files to check
+    #[clap(required = true, parse(from_os_str))]
+    pub files: Vec<std::path::PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct FsckArgs {
+    /// Move corrupt and stray files to `.quarantine` and renumber their stacks
+    #[clap(long)]
+    pub repair: bool,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -9,7 +9,10 @@ mod args;
 mod logger;
 
 fn main() {
-    human_panic::setup_panic!();
+    #[allow(deprecated)]
+    {
+        human_panic::setup_panic!();
+    }
     let result = run();
     proc_exit::exit(result);
 }
@@ -34,27 +37,134 @@ fn run() -> proc_exit::ExitResult {
 
     logger::init_logging(args.verbose.clone(), colored_stderr);
 
+    if let Some(output_path) = args.dump_config.as_deref() {
+        return dump_config(output_path);
+    }
+
     let subcommand = args.subcommand;
     let push_args = args.push;
     match subcommand.unwrap_or(args::Subcommand::Push(push_args)) {
         args::Subcommand::Push(sub_args) => push(sub_args),
         args::Subcommand::List(sub_args) => list(sub_args, colored_stdout),
+        args::Subcommand::Show(sub_args) => show(sub_args, colored_stdout),
+        args::Subcommand::Diff(sub_args) => diff(sub_args, colored_stdout),
+        args::Subcommand::RangeDiff(sub_args) => range_diff(sub_args, colored_stdout),
+        args::Subcommand::Lost(sub_args) => lost(sub_args, colored_stdout),
+        args::Subcommand::History(sub_args) => history(sub_args, colored_stdout),
         args::Subcommand::Clear(sub_args) => clear(sub_args),
         args::Subcommand::Drop(sub_args) => drop(sub_args),
         args::Subcommand::Pop(sub_args) => apply(sub_args, true),
         args::Subcommand::Apply(sub_args) => apply(sub_args, false),
+        args::Subcommand::Pin(sub_args) => pin(sub_args, true),
+        args::Subcommand::Unpin(sub_args) => pin(sub_args, false),
+        args::Subcommand::Prune(sub_args) => prune(sub_args),
         args::Subcommand::Stacks(sub_args) => stacks(sub_args),
+        args::Subcommand::Stack(sub_command) => stack(sub_command),
+        args::Subcommand::Trash(sub_command) => trash(sub_command),
+        args::Subcommand::Move(sub_args) => transfer(sub_args, true),
+        args::Subcommand::Copy(sub_args) => transfer(sub_args, false),
+        args::Subcommand::Export(sub_args) => export(sub_args),
+        args::Subcommand::Import(sub_args) => import(sub_args),
+        args::Subcommand::Stores(sub_args) => stores(sub_args),
+        args::Subcommand::Attach(sub_args) => attach(sub_args),
+        args::Subcommand::Migrate(sub_args) => migrate(sub_args),
+        args::Subcommand::Schema(sub_args) => schema(sub_args),
+        args::Subcommand::Validate(sub_args) => validate(sub_args),
+        args::Subcommand::Fsck(sub_args) => fsck(sub_args),
     }
 }
 
-fn push(args: args::PushArgs) -> proc_exit::ExitResult {
+fn dump_config(output_path: &std::path::Path) -> proc_exit::ExitResult {
+    let (_, mut repo_config, store) = open_store()?;
+
+    // Show the effective settings for every stack, not just configured ones
+    for stack in store.stacks() {
+        repo_config.stacks.entry(stack.name).or_default();
+    }
+
+    let output = repo_config.to_string();
+    if output_path == std::path::Path::new("-") {
+        std::io::stdout().write_all(output.as_bytes())?;
+    } else {
+        std::fs::write(output_path, &output)?;
+    }
+
+    Ok(())
+}
+
+// The repository around the current directory, its config and where its snapshots are kept
+fn open_store() -> Result<
+    (
+        git_branch_stash::git::GitRepo,
+        git_branch_stash::config::RepoConfig,
+        git_branch_stash::Store,
+    ),
+    proc_exit::Exit,
+> {
     let cwd = std::env::current_dir().with_code(proc_exit::Code::USAGE_ERR)?;
     let repo = git2::Repository::discover(&cwd).with_code(proc_exit::Code::USAGE_ERR)?;
     let repo = git_branch_stash::git::GitRepo::new(repo);
-    let mut stack = git_branch_stash::Stack::new(&args.stack, &repo);
-
     let repo_config = git_branch_stash::config::RepoConfig::from_all(repo.raw())
         .with_code(proc_exit::Code::CONFIG_ERR)?;
+    let store = git_branch_stash::Store::from_config(&repo, &repo_config)
+        .with_code(proc_exit::Code::CONFIG_ERR)?;
+    Ok((repo, repo_config, store))
+}
+
+fn push(args: args::PushArgs) -> proc_exit::ExitResult {
+    let (repo, repo_config, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+
+    stack.configure(&repo_config);
+
+    let mut snapshot = capture(&repo, &repo_config.for_stack(&args.stack))?;
+    if let Some(name) = args.name.as_deref() {
+        git_branch_stash::check_name(name).with_code(proc_exit::Code::USAGE_ERR)?;
+        snapshot.insert_name(name);
+    }
+    if let Some(message) = args.message.as_deref() {
+        snapshot.insert_message(message);
+    }
+    let pushed = if args.force {
+        stack.replace(snapshot)
+    } else {
+        stack.push(snapshot)
+    };
+    match pushed {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(proc_exit::Code::USAGE_ERR
+                .with_message(format!("{}, use --force to replace it", err)));
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+fn capture(
+    repo: &git_branch_stash::git::GitRepo,
+    repo_config: &git_branch_stash::config::RepoConfig,
+) -> Result<git_branch_stash::Snapshot, proc_exit::Exit> {
+    if repo.is_dirty() {
+        log::warn!("Working tree is dirty, only capturing committed changes");
+    }
+
+    let mut snapshot = current_branches(repo, repo_config)?;
+    if let Some(worktree) = repo.raw().workdir() {
+        snapshot.insert_worktree(worktree);
+    }
+    snapshot.insert_time(std::time::SystemTime::now());
+    Ok(snapshot)
+}
+
+// The branches as `push` would record them, without the snapshot-level metadata
+fn current_branches(
+    repo: &git_branch_stash::git::GitRepo,
+    repo_config: &git_branch_stash::config::RepoConfig,
+) -> Result<git_branch_stash::Snapshot, proc_exit::Exit> {
     let protected = git_branch_stash::git::ProtectedBranches::new(
         repo_config.protected_branches().iter().map(|s| s.as_str()),
     )
@@ -62,20 +172,24 @@ fn push(args: args::PushArgs) -> proc_exit::ExitResult {
     let branches = git_branch_stash::git::Branches::new(repo.local_branches());
     let protected_branches = branches.protected(&protected);
 
-    stack.capacity(repo_config.capacity());
-
-    if repo.is_dirty() {
-        log::warn!("Working tree is dirty, only capturing committed changes");
-    }
-
     let mut snapshot =
-        git_branch_stash::Snapshot::from_repo(&repo).with_code(proc_exit::Code::FAILURE)?;
-    if let Some(message) = args.message.as_deref() {
-        snapshot.insert_message(message);
-    }
-    snapshot.insert_parent(&repo, &branches, &protected_branches);
-    stack.push(snapshot)?;
+        git_branch_stash::Snapshot::from_repo(repo).with_code(proc_exit::Code::FAILURE)?;
+    filter_branches(&mut snapshot, repo_config)?;
+    snapshot.insert_parent(repo, &branches, &protected_branches);
+    Ok(snapshot)
+}
 
+fn filter_branches(
+    snapshot: &mut git_branch_stash::Snapshot,
+    repo_config: &git_branch_stash::config::RepoConfig,
+) -> Result<(), proc_exit::Exit> {
+    if let Some(patterns) = repo_config.branches() {
+        // Same glob syntax as protected branches
+        let filter =
+            git_branch_stash::git::ProtectedBranches::new(patterns.iter().map(|s| s.as_str()))
+                .with_code(proc_exit::Code::CONFIG_ERR)?;
+        snapshot.branches.retain(|b| filter.is_protected(&b.name));
+    }
     Ok(())
 }
 
@@ -86,10 +200,10 @@ fn list(args: args::ListArgs, colored: bool) -> proc_exit::ExitResult {
         Palette::plain()
     };
 
-    let cwd = std::env::current_dir().with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git2::Repository::discover(&cwd).with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git_branch_stash::git::GitRepo::new(repo);
-    let stack = git_branch_stash::Stack::new(&args.stack, &repo);
+    let (_, _, store) = open_store()?;
+    let stack = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
 
     let snapshots: Vec<_> = stack.iter().collect();
     for (i, snapshot_path) in snapshots.iter().enumerate() {
@@ -102,26 +216,38 @@ fn list(args: args::ListArgs, colored: bool) -> proc_exit::ExitResult {
             Ok(snapshot) => snapshot,
             Err(err) => {
                 log::error!(
-                    "Failed to load snapshot {}: {}",
+                    "Failed to load snapshot {}: {}, see `git branch-stash fsck`",
                     snapshot_path.display(),
                     err
                 );
                 continue;
             }
         };
-        match snapshot.metadata.get("message") {
-            Some(message) => {
+        if let Some(name) = snapshot.name() {
+            writeln!(
+                std::io::stdout(),
+                "{}",
+                style.paint(format_args!("Name: {}@{{{}}}", stack.name, name))
+            )?;
+        }
+        let pinned = if snapshot.is_pinned() {
+            " (pinned)"
+        } else {
+            ""
+        };
+        match snapshot.messages().as_slice() {
+            [] => {
                 writeln!(
                     std::io::stdout(),
                     "{}",
-                    style.paint(format_args!("Message: {}", message))
+                    style.paint(format_args!("Path: {}{}", snapshot_path.display(), pinned))
                 )?;
             }
-            None => {
+            messages => {
                 writeln!(
                     std::io::stdout(),
                     "{}",
-                    style.paint(format_args!("Path: {}", snapshot_path.display()))
+                    style.paint(format_args!("Message: {}{}", messages.join("; "), pinned))
                 )?;
             }
         }
@@ -149,6 +275,525 @@ fn list(args: args::ListArgs, colored: bool) -> proc_exit::ExitResult {
     Ok(())
 }
 
+fn show(args: args::ShowArgs, colored: bool) -> proc_exit::ExitResult {
+    let palette = if colored {
+        Palette::colored()
+    } else {
+        Palette::plain()
+    };
+
+    let (repo, _, store) = open_store()?;
+
+    let handle = args
+        .snapshot
+        .resolve(&store)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    let snapshot = handle.load().with_code(proc_exit::Code::FAILURE)?;
+
+    let mut stdout = std::io::stdout();
+    writeln!(
+        stdout,
+        "{}",
+        palette.good.paint(format_args!(
+            "{} ({})",
+            snapshot_label(&handle.stack, &handle.path),
+            handle.path.display()
+        ))
+    )?;
+    let now = std::time::SystemTime::now();
+    for (key, value) in snapshot.metadata.iter() {
+        let value = match (key.as_str(), value) {
+            ("time" | "updated" | "trashed", serde_json::Value::Number(seconds)) => {
+                let time = std::time::UNIX_EPOCH
+                    + std::time::Duration::from_secs(seconds.as_u64().unwrap_or_default());
+                format!(
+                    "{} ago",
+                    format_age(now.duration_since(time).unwrap_or_default())
+                )
+            }
+            ("message", _) => snapshot.messages().join("; "),
+            (_, serde_json::Value::String(value)) => value.clone(),
+            (_, value) => value.to_string(),
+        };
+        writeln!(stdout, "{}: {}", key, value)?;
+    }
+
+    for branch in snapshot.branches.iter() {
+        writeln!(stdout)?;
+        match branch.metadata.get("parent") {
+            Some(serde_json::Value::String(parent)) if parent != &branch.name => writeln!(
+                stdout,
+                "{} {}",
+                palette.info.paint(&branch.name),
+                palette.hint.paint(format_args!("(from {})", parent))
+            )?,
+            _ => writeln!(stdout, "{}", palette.info.paint(&branch.name))?,
+        }
+        let range = match snapshot.branch_commits(&repo, branch) {
+            Some(range) => range,
+            None => {
+                writeln!(
+                    stdout,
+                    "  {} {}",
+                    palette.hint.paint(short_id(branch.id)),
+                    palette.error.paint("(missing)")
+                )?;
+                continue;
+            }
+        };
+        for commit in range.commits {
+            writeln!(
+                stdout,
+                "  {} {}",
+                palette.hint.paint(short_id(commit.id)),
+                commit.summary
+            )?;
+            if args.stat {
+                let stat =
+                    commit_stat(repo.raw(), commit.id).with_code(proc_exit::Code::FAILURE)?;
+                for line in stat.lines() {
+                    writeln!(stdout, "    {}", line.trim_start())?;
+                }
+            }
+        }
+        if range.base.is_none() {
+            writeln!(
+                stdout,
+                "  {}",
+                palette.hint.paint("... (fork point unknown)")
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn diff(args: args::DiffArgs, colored: bool) -> proc_exit::ExitResult {
+    let palette = if colored {
+        Palette::colored()
+    } else {
+        Palette::plain()
+    };
+
+    let (repo, repo_config, store) = open_store()?;
+
+    let handle = args
+        .snapshot
+        .resolve(&store)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    let old = handle.load().with_code(proc_exit::Code::FAILURE)?;
+    let (new, since) = match &args.other {
+        Some(other) => {
+            let other = other
+                .resolve(&store)
+                .with_code(proc_exit::Code::USAGE_ERR)?
+                .load()
+                .with_code(proc_exit::Code::FAILURE)?;
+            (
+                other,
+                format!(
+                    "between {} and {}",
+                    args.snapshot,
+                    args.other.as_ref().unwrap()
+                ),
+            )
+        }
+        None => (
+            current_branches(&repo, &repo_config.for_stack(&handle.stack.name))?,
+            format!("since {}", args.snapshot),
+        ),
+    };
+
+    let mut diff = git_branch_stash::SnapshotDiff::new(&repo, &old, &new);
+    if args.commits {
+        diff = diff.with_commits(&repo, &old, &new);
+    }
+    if args.json {
+        let json = serde_json::to_string_pretty(&diff).with_code(proc_exit::Code::FAILURE)?;
+        writeln!(std::io::stdout(), "{}", json)?;
+        return Ok(());
+    }
+    if diff.is_empty() {
+        log::info!("No changes {}", since);
+    }
+    write_diff(&diff, palette)?;
+
+    Ok(())
+}
+
+fn write_diff(diff: &git_branch_stash::SnapshotDiff, palette: Palette) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    for change in diff.changes.iter() {
+        match change {
+            git_branch_stash::BranchChange::Created { name, id } => writeln!(
+                stdout,
+                "{}",
+                palette
+                    .good
+                    .paint(format_args!("+ {} {}", name, short_id(*id)))
+            )?,
+            git_branch_stash::BranchChange::Deleted { name, id } => writeln!(
+                stdout,
+                "{}",
+                palette
+                    .error
+                    .paint(format_args!("- {} {}", name, short_id(*id)))
+            )?,
+            git_branch_stash::BranchChange::Moved {
+                name,
+                from,
+                to,
+                ahead,
+                behind,
+            } => {
+                let counts = match (ahead, behind) {
+                    (Some(ahead), Some(behind)) => format!("{} ahead, {} behind", ahead, behind),
+                    _ => "commits missing".to_owned(),
+                };
+                writeln!(
+                    stdout,
+                    "{} {}",
+                    palette.warn.paint(format_args!(
+                        "~ {} {}..{}",
+                        name,
+                        short_id(*from),
+                        short_id(*to)
+                    )),
+                    palette.hint.paint(format_args!("({})", counts))
+                )?
+            }
+            git_branch_stash::BranchChange::Renamed { from, to, id } => writeln!(
+                stdout,
+                "{}",
+                palette
+                    .info
+                    .paint(format_args!("> {} -> {} {}", from, to, short_id(*id)))
+            )?,
+            git_branch_stash::BranchChange::Reparented { name, from, to } => writeln!(
+                stdout,
+                "{}",
+                palette.info.paint(format_args!(
+                    "^ {} {} -> {}",
+                    name,
+                    from.as_deref().unwrap_or("(none)"),
+                    to.as_deref().unwrap_or("(none)")
+                ))
+            )?,
+        }
+    }
+    for branch in diff.commits.iter() {
+        writeln!(stdout)?;
+        writeln!(stdout, "{}", palette.info.paint(&branch.name))?;
+        for change in branch.changes.iter() {
+            match change {
+                git_branch_stash::CommitChange::Added { id, summary } => writeln!(
+                    stdout,
+                    "  {} {}",
+                    palette.good.paint(format_args!("+ {}", short_id(*id))),
+                    summary
+                )?,
+                git_branch_stash::CommitChange::Dropped { id, summary } => writeln!(
+                    stdout,
+                    "  {} {}",
+                    palette.error.paint(format_args!("- {}", short_id(*id))),
+                    summary
+                )?,
+                git_branch_stash::CommitChange::Rewritten { from, to, summary } => writeln!(
+                    stdout,
+                    "  {} {}",
+                    palette
+                        .warn
+                        .paint(format_args!("~ {} -> {}", short_id(*from), short_id(*to))),
+                    summary
+                )?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn range_diff(args: args::RangeDiffArgs, colored: bool) -> proc_exit::ExitResult {
+    let palette = if colored {
+        Palette::colored()
+    } else {
+        Palette::plain()
+    };
+
+    let (repo, repo_config, store) = open_store()?;
+
+    let handle = args
+        .snapshot
+        .resolve(&store)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    let old = handle.load().with_code(proc_exit::Code::FAILURE)?;
+    let new = current_branches(&repo, &repo_config.for_stack(&handle.stack.name))?;
+    let diff = git_branch_stash::RangeDiff::new(&repo, &args.branch, &old, &new)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&diff).with_code(proc_exit::Code::FAILURE)?;
+        writeln!(std::io::stdout(), "{}", json)?;
+        return Ok(());
+    }
+    let mut stdout = std::io::stdout();
+    for change in diff.changes.iter() {
+        match change {
+            git_branch_stash::RangeChange::Unchanged { from, to, summary } => writeln!(
+                stdout,
+                "{} {}",
+                palette
+                    .hint
+                    .paint(format_args!("= {} -> {}", short_id(*from), short_id(*to))),
+                summary
+            )?,
+            git_branch_stash::RangeChange::Modified { from, to, summary } => writeln!(
+                stdout,
+                "{} {}",
+                palette
+                    .warn
+                    .paint(format_args!("~ {} -> {}", short_id(*from), short_id(*to))),
+                summary
+            )?,
+            git_branch_stash::RangeChange::Added { id, summary } => writeln!(
+                stdout,
+                "{} {}",
+                palette.good.paint(format_args!("+ {}", short_id(*id))),
+                summary
+            )?,
+            git_branch_stash::RangeChange::Removed { id, summary } => writeln!(
+                stdout,
+                "{} {}",
+                palette.error.paint(format_args!("- {}", short_id(*id))),
+                summary
+            )?,
+            git_branch_stash::RangeChange::Upstream { id, summary } => writeln!(
+                stdout,
+                "{} {} {}",
+                palette.hint.paint(format_args!("= {}", short_id(*id))),
+                summary,
+                palette.hint.paint("(upstream)")
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+fn lost(args: args::LostArgs, colored: bool) -> proc_exit::ExitResult {
+    let palette = if colored {
+        Palette::colored()
+    } else {
+        Palette::plain()
+    };
+
+    let (mut repo, _, store) = open_store()?;
+
+    let mut snapshots = Vec::new();
+    if args.all {
+        for stack in store.stacks() {
+            // Newest first, so commits are credited to the latest snapshot with them
+            for path in stack.iter().rev() {
+                match git_branch_stash::Snapshot::load(&path) {
+                    Ok(snapshot) => snapshots.push((snapshot_label(&stack, &path), snapshot)),
+                    Err(err) => log::error!(
+                        "Failed to load snapshot {}: {}, see `git branch-stash fsck`",
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        }
+    } else {
+        let handle = args
+            .snapshot
+            .resolve(&store)
+            .with_code(proc_exit::Code::USAGE_ERR)?;
+        let snapshot = handle.load().with_code(proc_exit::Code::FAILURE)?;
+        snapshots.push((snapshot_label(&handle.stack, &handle.path), snapshot));
+    }
+
+    let mut stdout = std::io::stdout();
+    let mut seen = std::collections::HashSet::new();
+    let mut found = Vec::new();
+    for (label, snapshot) in snapshots {
+        let mut lost = git_branch_stash::lost_branches(&repo, &snapshot)
+            .with_code(proc_exit::Code::FAILURE)?;
+        for branch in lost.iter_mut() {
+            branch.commits.retain(|c| seen.insert(c.id));
+        }
+        lost.retain(|b| !b.commits.is_empty());
+        if lost.is_empty() {
+            continue;
+        }
+
+        writeln!(stdout, "{}", palette.good.paint(label))?;
+        for branch in lost {
+            writeln!(stdout, "{}", palette.info.paint(&branch.name))?;
+            for commit in branch.commits.iter() {
+                writeln!(
+                    stdout,
+                    "  {} {}",
+                    palette.hint.paint(short_id(commit.id)),
+                    commit.summary
+                )?;
+            }
+            found.push(branch);
+        }
+        writeln!(stdout)?;
+    }
+
+    if found.is_empty() {
+        log::info!("No lost commits");
+    } else if args.recreate {
+        for branch in found {
+            let mut name = format!("lost/{}", branch.name);
+            if repo.find_local_branch(&name).is_some() {
+                name = format!("{}-{}", name, short_id(branch.id));
+            }
+            if repo.find_local_branch(&name).is_some() {
+                log::warn!("Skipping `{}`, the branch already exists", name);
+                continue;
+            }
+            repo.branch(&name, branch.id)
+                .with_code(proc_exit::Code::FAILURE)?;
+            log::info!("Created `{}` at {}", name, short_id(branch.id));
+        }
+    } else {
+        log::info!(
+            "Run with `--recreate` to create `lost/<branch>` branches pointing at these commits"
+        );
+    }
+
+    Ok(())
+}
+
+fn history(args: args::HistoryArgs, colored: bool) -> proc_exit::ExitResult {
+    let palette = if colored {
+        Palette::colored()
+    } else {
+        Palette::plain()
+    };
+
+    let (repo, _, store) = open_store()?;
+
+    let stacks: Vec<_> = if args.all {
+        store.stacks().collect()
+    } else {
+        vec![store
+            .stack(&args.stack)
+            .with_code(proc_exit::Code::USAGE_ERR)?]
+    };
+
+    let mut stdout = std::io::stdout();
+    let now = std::time::SystemTime::now();
+    let mut found = false;
+    for stack in stacks {
+        let paths: Vec<_> = stack.iter().collect();
+        let count = paths.len();
+        let snapshots = paths.into_iter().enumerate().filter_map(|(i, path)| {
+            match git_branch_stash::Snapshot::load(&path) {
+                Ok(snapshot) => {
+                    let label = format!("{}@{{{}}}", stack.name, count - 1 - i);
+                    Some(((label, snapshot.time()), snapshot))
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to load snapshot {}: {}, see `git branch-stash fsck`",
+                        path.display(),
+                        err
+                    );
+                    None
+                }
+            }
+        });
+        let entries: Vec<_> =
+            git_branch_stash::BranchHistory::new(&repo, &args.branch, snapshots).collect();
+        if entries.is_empty() {
+            continue;
+        }
+        if found {
+            writeln!(stdout)?;
+        }
+        found = true;
+
+        writeln!(stdout, "{}", palette.good.paint(&stack.name))?;
+        for entry in entries {
+            let (marker, style) = match entry.event {
+                git_branch_stash::HistoryEvent::Recorded => ("*", palette.info),
+                git_branch_stash::HistoryEvent::Created => ("+", palette.good),
+                git_branch_stash::HistoryEvent::Moved => ("~", palette.info),
+                git_branch_stash::HistoryEvent::Rewritten => ("!", palette.warn),
+                git_branch_stash::HistoryEvent::Reparented => ("^", palette.info),
+                git_branch_stash::HistoryEvent::Deleted => ("-", palette.error),
+            };
+            let ((first, time), (last, _)) = (&entry.first, &entry.last);
+            let snapshots = if entry.count == 1 {
+                first.clone()
+            } else {
+                format!("{}..{}", first, last)
+            };
+            let age = time
+                .and_then(|time| now.duration_since(time).ok())
+                .map(|age| format!(", {} ago", format_age(age)))
+                .unwrap_or_default();
+            let state = match entry.branch.as_ref() {
+                Some(branch) => {
+                    let summary = match repo.find_commit(branch.id) {
+                        Some(commit) => commit.summary.to_string(),
+                        None => "(missing)".to_owned(),
+                    };
+                    let parent = match branch.metadata.get("parent") {
+                        Some(serde_json::Value::String(parent)) if parent != &branch.name => {
+                            format!(" (from {})", parent)
+                        }
+                        _ => String::new(),
+                    };
+                    format!("{} {}{}", short_id(branch.id), summary, parent)
+                }
+                None => "(deleted)".to_owned(),
+            };
+            writeln!(
+                stdout,
+                "{} {} {}",
+                style.paint(marker),
+                state,
+                palette.hint.paint(format_args!("[{}{}]", snapshots, age))
+            )?;
+        }
+    }
+
+    if !found {
+        log::info!("`{}` is not in any snapshot", args.branch);
+    }
+
+    Ok(())
+}
+
+fn snapshot_label(stack: &git_branch_stash::Stack, path: &std::path::Path) -> String {
+    let position = stack
+        .iter()
+        .rev()
+        .position(|p| p == path)
+        .unwrap_or_default();
+    format!("{}@{{{}}}", stack.name, position)
+}
+
+fn short_id(id: git2::Oid) -> String {
+    id.to_string()[..7].to_owned()
+}
+
+fn commit_stat(repo: &git2::Repository, id: git2::Oid) -> Result<String, git2::Error> {
+    let commit = repo.find_commit(id)?;
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    let stats = diff.stats()?;
+    let buf = stats.to_buf(git2::DiffStatsFormat::FULL, 72)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
 #[derive(Copy, Clone, Debug)]
 #[allow(dead_code)]
 struct Palette {
@@ -182,37 +827,85 @@ impl Palette {
 }
 
 fn clear(args: args::ClearArgs) -> proc_exit::ExitResult {
-    let cwd = std::env::current_dir().with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git2::Repository::discover(&cwd).with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git_branch_stash::git::GitRepo::new(repo);
-    let mut stack = git_branch_stash::Stack::new(&args.stack, &repo);
+    let (_, _, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
 
-    stack.clear();
+    if args.all {
+        stack.clear_all().with_code(proc_exit::Code::FAILURE)?;
+    } else {
+        stack.clear().with_code(proc_exit::Code::FAILURE)?;
+    }
 
     Ok(())
 }
 
 fn drop(args: args::DropArgs) -> proc_exit::ExitResult {
-    let cwd = std::env::current_dir().with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git2::Repository::discover(&cwd).with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git_branch_stash::git::GitRepo::new(repo);
-    let mut stack = git_branch_stash::Stack::new(&args.stack, &repo);
+    let (_, _, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.snapshot.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    if stack.peek().is_none() {
+        log::warn!("Nothing to drop");
+        return Ok(());
+    }
 
-    stack.pop();
+    let handle = args
+        .snapshot
+        .resolve(&store)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    stack
+        .remove_snapshot(&handle.path)
+        .with_code(proc_exit::Code::FAILURE)?;
 
     Ok(())
 }
 
 fn apply(args: args::ApplyArgs, pop: bool) -> proc_exit::ExitResult {
-    let cwd = std::env::current_dir().with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git2::Repository::discover(&cwd).with_code(proc_exit::Code::USAGE_ERR)?;
-    let mut repo = git_branch_stash::git::GitRepo::new(repo);
-    let mut stack = git_branch_stash::Stack::new(&args.stack, &repo);
+    let (mut repo, repo_config, store) = open_store()?;
+    repo.set_offline(args.offline);
+    let mut stack = store
+        .stack(&args.snapshot.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
 
     match stack.peek() {
-        Some(last) => {
-            let snapshot =
-                git_branch_stash::Snapshot::load(&last).with_code(proc_exit::Code::FAILURE)?;
+        Some(_) => {
+            let handle = args
+                .snapshot
+                .resolve(&store)
+                .with_code(proc_exit::Code::USAGE_ERR)?;
+            let snapshot = handle.load().with_code(proc_exit::Code::FAILURE)?;
+
+            if let Some(source) = args.from.as_deref() {
+                snapshot
+                    .fetch(&mut repo, source)
+                    .with_code(proc_exit::Code::FAILURE)?;
+            }
+            snapshot
+                .recover(&mut repo)
+                .with_code(proc_exit::Code::FAILURE)?;
+            let missing = snapshot.missing_commits(&repo);
+            if !missing.is_empty() {
+                for branch in missing.iter() {
+                    log::error!("Commit {} for {} not found", branch.id, branch.name);
+                }
+                return Err(proc_exit::Code::FAILURE
+                    .with_message("Snapshot references missing commits, aborting"));
+            }
+
+            if repo_config.for_stack(&args.snapshot.stack).auto_capture() {
+                let mut backup = store
+                    .stack(git_branch_stash::Stack::DEFAULT_STACK)
+                    .with_code(proc_exit::Code::USAGE_ERR)?;
+                backup.configure(&repo_config);
+                let mut current = capture(&repo, &repo_config.for_stack(&backup.name))?;
+                current.insert_message(&format!("Before applying {}", args.snapshot));
+                let path = handle
+                    .push_beside(&mut backup, current)
+                    .with_code(proc_exit::Code::FAILURE)?;
+                log::debug!("Captured current branches as {}", path.display());
+            }
 
             let stash_id = git_branch_stash::git::stash_push(&mut repo, "branch-stash");
             if repo.is_dirty() {
@@ -228,7 +921,9 @@ fn apply(args: args::ApplyArgs, pop: bool) -> proc_exit::ExitResult {
 
             git_branch_stash::git::stash_pop(&mut repo, stash_id);
             if pop {
-                let _ = std::fs::remove_file(&last);
+                stack
+                    .remove_snapshot(&handle.path)
+                    .with_code(proc_exit::Code::FAILURE)?;
             }
         }
         None => {
@@ -239,14 +934,393 @@ fn apply(args: args::ApplyArgs, pop: bool) -> proc_exit::ExitResult {
     Ok(())
 }
 
+fn pin(args: args::PinArgs, pinned: bool) -> proc_exit::ExitResult {
+    let (_, _, store) = open_store()?;
+
+    let mut handle = args
+        .snapshot
+        .resolve(&store)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    handle
+        .stack
+        .set_pinned(&handle.path, pinned)
+        .with_code(proc_exit::Code::FAILURE)?;
+
+    Ok(())
+}
+
+fn prune(args: args::PruneArgs) -> proc_exit::ExitResult {
+    let (_, repo_config, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+
+    let retention = repo_config.for_stack(&args.stack).retention();
+    if !retention.is_enabled() {
+        log::warn!("No retention rules configured, see `branch-stash.keep-*`");
+        return Ok(());
+    }
+    stack.retention(retention);
+
+    let now = std::time::SystemTime::now();
+    let expired = if args.dry_run {
+        stack.expired(now)
+    } else {
+        stack.prune(now)
+    }
+    .with_code(proc_exit::Code::FAILURE)?;
+    let action = if args.dry_run {
+        "Would remove"
+    } else {
+        "Removed"
+    };
+    for path in expired {
+        writeln!(std::io::stdout(), "{} {}", action, path.display())?;
+    }
+
+    Ok(())
+}
+
 fn stacks(_args: args::StacksArgs) -> proc_exit::ExitResult {
+    let (_, _, store) = open_store()?;
+
+    // `Store::stacks` keeps nested stacks right after their parent, so print it as a tree
+    let mut previous: Vec<String> = Vec::new();
+    for stack in store.stacks() {
+        let parts: Vec<_> = stack.name.split('/').map(|p| p.to_owned()).collect();
+        let (leaf, groups) = parts.split_last().unwrap();
+        let common = previous
+            .iter()
+            .zip(groups.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, group) in groups.iter().enumerate().skip(common) {
+            writeln!(
+                std::io::stdout(),
+                "{:indent$}{}/",
+                "",
+                group,
+                indent = 2 * depth
+            )?;
+        }
+        writeln!(
+            std::io::stdout(),
+            "{:indent$}{}",
+            "",
+            leaf,
+            indent = 2 * groups.len()
+        )?;
+        previous = parts;
+    }
+
+    Ok(())
+}
+
+fn stack(command: args::StackCommand) -> proc_exit::ExitResult {
+    let (_, _, store) = open_store()?;
+
+    match command {
+        args::StackCommand::Rename(args) => store
+            .rename_stack(&args.from, &args.to)
+            .with_code(proc_exit::Code::FAILURE)?,
+        args::StackCommand::Copy(args) => store
+            .copy_stack(&args.from, &args.to)
+            .with_code(proc_exit::Code::FAILURE)?,
+        args::StackCommand::Delete(args) => store
+            .delete_stack(&args.stack)
+            .with_code(proc_exit::Code::FAILURE)?,
+    }
+
+    Ok(())
+}
+
+fn trash(command: args::TrashCommand) -> proc_exit::ExitResult {
+    let (_, repo_config, store) = open_store()?;
+
+    match command {
+        args::TrashCommand::List(args) => {
+            let now = std::time::SystemTime::now();
+            for trash in store.trash() {
+                if args.stack.as_deref().map_or(false, |s| s != trash.name) {
+                    continue;
+                }
+                // Don't offer snapshots that are only waiting to be purged
+                let mut stack = store
+                    .stack(&trash.name)
+                    .with_code(proc_exit::Code::USAGE_ERR)?;
+                stack.configure(&repo_config);
+                stack.purge_trash(now)?;
+                for (i, path) in trash.iter().rev().enumerate() {
+                    let snapshot = match git_branch_stash::Snapshot::load(&path) {
+                        Ok(snapshot) => snapshot,
+                        Err(err) => {
+                            log::error!("Failed to load snapshot {}: {}", path.display(), err);
+                            continue;
+                        }
+                    };
+                    let age = snapshot
+                        .trashed()
+                        .and_then(|t| now.duration_since(t).ok())
+                        .map(|age| format!("removed {} ago", format_age(age)))
+                        .unwrap_or_else(|| "removed".to_owned());
+                    let label = snapshot
+                        .name()
+                        .or_else(|| snapshot.message())
+                        .map(|s| s.to_owned())
+                        .unwrap_or_else(|| format!("{} branches", snapshot.branches.len()));
+                    writeln!(
+                        std::io::stdout(),
+                        "{} {}: {} ({})",
+                        trash.name,
+                        i,
+                        label,
+                        age
+                    )?;
+                }
+            }
+        }
+        args::TrashCommand::Restore(args) => {
+            let mut stack = store
+                .stack(&args.stack)
+                .with_code(proc_exit::Code::USAGE_ERR)?;
+            stack.configure(&repo_config);
+            // Number entries the same way `trash list` does
+            stack.purge_trash(std::time::SystemTime::now())?;
+            let trash = stack.trash().expect("store stacks have a trash");
+            let trashed: Vec<_> = trash.iter().rev().collect();
+            let path = trashed.get(args.index).ok_or_else(|| {
+                proc_exit::Code::USAGE_ERR.with_message(format!(
+                    "No entry {} in the trash for `{}`, it has {}",
+                    args.index,
+                    args.stack,
+                    trashed.len()
+                ))
+            })?;
+            let restored = stack.restore(path).with_code(proc_exit::Code::FAILURE)?;
+            log::info!("Restored as {}", restored.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn format_age(age: std::time::Duration) -> String {
+    let seconds = age.as_secs();
+    let (count, unit) = if seconds < 60 {
+        (seconds, "second")
+    } else if seconds < 60 * 60 {
+        (seconds / 60, "minute")
+    } else if seconds < 24 * 60 * 60 {
+        (seconds / 60 / 60, "hour")
+    } else {
+        (seconds / 24 / 60 / 60, "day")
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{} {}{}", count, unit, plural)
+}
+
+fn transfer(args: args::TransferArgs, remove: bool) -> proc_exit::ExitResult {
+    let (_, repo_config, store) = open_store()?;
+    let mut target = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    target.configure(&repo_config);
+
+    let handle = args
+        .snapshot
+        .resolve(&store)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+    let source = handle.path.clone();
+    let path = if remove {
+        handle.move_to(&mut target)
+    } else {
+        handle.copy_to(&mut target)
+    }
+    .with_code(proc_exit::Code::FAILURE)?;
+    log::debug!("{} -> {}", source.display(), path.display());
+
+    Ok(())
+}
+
+fn export(args: args::ExportArgs) -> proc_exit::ExitResult {
+    let (repo, _, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.snapshot.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+
+    match stack.peek() {
+        Some(_) => {
+            let handle = args
+                .snapshot
+                .resolve(&store)
+                .with_code(proc_exit::Code::USAGE_ERR)?;
+            let snapshot = handle.load().with_code(proc_exit::Code::FAILURE)?;
+            git_branch_stash::bundle::export(repo.raw(), &snapshot, &args.output)
+                .with_code(proc_exit::Code::FAILURE)?;
+            log::info!(
+                "Exported {} to {}",
+                handle.path.display(),
+                args.output.display()
+            );
+        }
+        None => {
+            log::warn!("Nothing to export");
+        }
+    }
+
+    Ok(())
+}
+
+fn import(args: args::ImportArgs) -> proc_exit::ExitResult {
+    let (repo, repo_config, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+
+    stack.configure(&repo_config);
+
+    let snapshot = git_branch_stash::bundle::import(repo.raw(), &args.bundle)
+        .with_code(proc_exit::Code::FAILURE)?;
+    let path = stack.push(snapshot)?;
+    log::info!("Imported {} as {}", args.bundle.display(), path.display());
+
+    Ok(())
+}
+
+fn stores(args: args::StoresArgs) -> proc_exit::ExitResult {
+    for (id, store) in git_branch_stash::global_stores() {
+        let orphaned = store.is_orphaned();
+        if args.orphaned && !orphaned {
+            continue;
+        }
+        let manifest = store.manifest().unwrap_or_default();
+        let workdirs = manifest
+            .workdirs
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if orphaned {
+            writeln!(std::io::stdout(), "{} {} (orphaned)", id, workdirs)?;
+        } else {
+            writeln!(std::io::stdout(), "{} {}", id, workdirs)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn attach(args: args::AttachArgs) -> proc_exit::ExitResult {
     let cwd = std::env::current_dir().with_code(proc_exit::Code::USAGE_ERR)?;
     let repo = git2::Repository::discover(&cwd).with_code(proc_exit::Code::USAGE_ERR)?;
-    let repo = git_branch_stash::git::GitRepo::new(repo);
 
-    for stack in git_branch_stash::Stack::all(&repo) {
-        writeln!(std::io::stdout(), "{}", stack.name)?;
+    let store =
+        git_branch_stash::Store::global(&args.store).with_code(proc_exit::Code::USAGE_ERR)?;
+    if !store.root().exists() {
+        return Err(proc_exit::Code::USAGE_ERR
+            .with_message(format!("Snapshot store `{}` does not exist", args.store)));
+    }
+    if let Some(root) = store.manifest().and_then(|m| m.root) {
+        let current = git_branch_stash::Manifest::from_repo(&repo).root;
+        if current.as_deref() != Some(root.as_str()) {
+            log::warn!("Store `{}` was created for a different history", args.store);
+        }
     }
 
+    let repo_config = git_branch_stash::config::RepoConfig {
+        store: Some(git_branch_stash::config::StoreLocation::Global),
+        store_id: Some(args.store.clone()),
+        ..Default::default()
+    };
+    repo_config
+        .write_repo(&repo)
+        .with_code(proc_exit::Code::FAILURE)?;
+    store.record(&repo).with_code(proc_exit::Code::FAILURE)?;
+
+    Ok(())
+}
+
+fn migrate(args: args::MigrateArgs) -> proc_exit::ExitResult {
+    let (_, _, store) = open_store()?;
+    let mut stack = store
+        .stack(&args.stack)
+        .with_code(proc_exit::Code::USAGE_ERR)?;
+
+    let migrated = stack.migrate().with_code(proc_exit::Code::FAILURE)?;
+    log::info!("Migrated {} snapshots in {}", migrated, stack.name);
+
     Ok(())
 }
+
+fn fsck(args: args::FsckArgs) -> proc_exit::ExitResult {
+    let (repo, _, store) = open_store()?;
+
+    let mut problems = git_branch_stash::check(&store, &repo);
+    if args.repair && problems.iter().any(|p| p.is_repairable()) {
+        let repaired =
+            git_branch_stash::repair(&store, &problems).with_code(proc_exit::Code::FAILURE)?;
+        log::info!("Repaired {} problems", repaired);
+        problems = git_branch_stash::check(&store, &repo);
+    }
+
+    for problem in problems.iter() {
+        if problem.is_error() {
+            log::error!("{}", problem);
+        } else {
+            log::warn!("{}", problem);
+        }
+    }
+    if problems.iter().any(|p| p.is_error()) {
+        if !args.repair && problems.iter().any(|p| p.is_repairable()) {
+            log::info!("Run `git branch-stash fsck --repair` to set aside unusable files");
+        }
+        proc_exit::Code::FAILURE.ok()
+    } else {
+        Ok(())
+    }
+}
+
+fn schema(_args: args::SchemaArgs) -> proc_exit::ExitResult {
+    let schema = git_branch_stash::schema::snapshot_schema();
+    let schema = serde_json::to_string_pretty(&schema).with_code(proc_exit::Code::FAILURE)?;
+    writeln!(std::io::stdout(), "{}", schema)?;
+
+    Ok(())
+}
+
+fn validate(args: args::ValidateArgs) -> proc_exit::ExitResult {
+    let mut success = true;
+    for path in args.files.iter() {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                log::error!("{}: {}", path.display(), err);
+                success = false;
+                continue;
+            }
+        };
+        let value: serde_json::Value = match serde_json::from_slice(&data) {
+            Ok(value) => value,
+            Err(err) => {
+                log::error!("{}: {}", path.display(), err);
+                success = false;
+                continue;
+            }
+        };
+        let errors = git_branch_stash::schema::validate(&value);
+        if errors.is_empty() {
+            log::debug!("{}: valid", path.display());
+        } else {
+            for error in errors {
+                log::error!("{}: {}", path.display(), error);
+            }
+            success = false;
+        }
+    }
+
+    if success {
+        Ok(())
+    } else {
+        proc_exit::Code::FAILURE.ok()
+    }
+}